@@ -2,30 +2,91 @@
 pub enum Expr {
     Binary(Box<Expr>, Op, Box<Expr>),
     Number(f64),
+    Int(i64),
     Str(String),
     Name(String),
     Call(Box<Expr>, Vec<Box<Expr>>),
+    // f(1, y: 2): the function, the positional arguments, then the named ones
+    NamedCall(Box<Expr>, Vec<Box<Expr>>, Vec<(String, Box<Expr>)>),
     Lambda(Vec<String>, Box<Expr>),
     Pull,
     FinishedPipe,
     Block(Vec<Box<Expr>>),
     If(Box<Expr>, Box<Expr>, Box<Expr>),
     While(Box<Expr>, Box<Expr>),
+    // do body while cond
+    DoWhile(Box<Expr>, Box<Expr>),
     Assignment(String, Box<Expr>),
     Push(Box<Expr>),
     Bool(bool),
     Return(Box<Expr>),
+    Break,
+    Continue,
     Neg(Box<Expr>),
     Index(Box<Expr>, Box<Expr>),
+    // s[start:end], where both ends can be left out
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>),
+    // start, end, whether end is included
+    Range(Box<Expr>, Box<Expr>, bool),
+    Match(Box<Expr>, Vec<(Pattern, Box<Expr>)>),
+    // try body finally cleanup
+    Try(Box<Expr>, Box<Expr>),
+    // The value as a string, for the parts of an interpolated string
+    Stringify(Box<Expr>),
     //Attribute(Box<Expr>, String),
 }
 
+// An argument in a call, before calls are split into positional and named arguments
+#[derive(Debug, PartialEq, Clone)]
+pub enum Arg {
+    Positional(Box<Expr>),
+    Named(String, Box<Expr>),
+}
+
+/// Builds a call from its arguments. Named arguments have to come after all
+/// of the positional ones.
+pub fn call(func: Box<Expr>, args: Vec<Arg>) -> Result<Expr, &'static str> {
+    let mut positional = vec![];
+    let mut named = vec![];
+    for arg in args {
+        match arg {
+            Arg::Positional(_) if !named.is_empty() => return Err("positional arguments must come before named arguments"),
+            Arg::Positional(arg) => positional.push(arg),
+            Arg::Named(name, arg) => named.push((name, arg)),
+        }
+    }
+    if named.is_empty() {
+        Ok(Expr::Call(func, positional))
+    } else {
+        Ok(Expr::NamedCall(func, positional, named))
+    }
+}
+
+// Number patterns compare equal to both ints and floats, so they only need the f64.
+pub fn literal_as_f64(literal: &Expr) -> f64 {
+    match *literal {
+        Expr::Int(n) => n as f64,
+        Expr::Number(n) => n,
+        _ => panic!("{:?} is not a number literal", literal),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Pattern {
+    Number(f64),
+    Str(String),
+    Bool(bool),
+    Wildcard,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Op {
     Plus,
     Minus,
     Times,
     Slash,
+    DoubleSlash,
+    Power,
     Pipe,
     Percent,
     Greater,
@@ -34,11 +95,19 @@ pub enum Op {
     NotEquals,
     And,
     Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    // item in collection
+    In,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Top {
-    Use(String),
+    // The module's path, and the name given after "as", if any
+    Use(String, Option<String>),
     Definition(Definition),    
 }
 
@@ -46,14 +115,40 @@ pub enum Top {
 pub struct Prototype {
     pub name: String,
     pub args: Vec<String>,
+    // The default values of the last defaults.len() args
+    pub defaults: Vec<Box<Expr>>,
 }
 impl Prototype {
     pub fn new(name: String, args: Vec<String>) -> Prototype {
         Prototype {
-            name: name,
-            args: args,
+            name,
+            args,
+            defaults: vec![],
         }
     }
+    /// Builds a prototype from parameters that may have default values, like
+    /// the ones in `add(x, y=1)`. Only trailing parameters can have defaults.
+    pub fn with_defaults(name: String, params: Vec<(String, Option<Box<Expr>>)>) -> Result<Prototype, &'static str> {
+        let mut args = Vec::with_capacity(params.len());
+        let mut defaults = vec![];
+        for (arg, default) in params {
+            match default {
+                Some(default) => defaults.push(default),
+                None if !defaults.is_empty() => return Err("parameters without default values must come before the ones with them"),
+                None => {},
+            }
+            args.push(arg);
+        }
+        Ok(Prototype {
+            name,
+            args,
+            defaults,
+        })
+    }
+    /// How many arguments a call has to pass.
+    pub fn required_args(&self) -> usize {
+        self.args.len() - self.defaults.len()
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
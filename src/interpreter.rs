@@ -2,7 +2,8 @@ use std::collections::HashMap;
 use std::fmt;
 use std::thread;
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Once};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::cell::RefCell;
 use std::cmp::PartialEq;
 use std::io;
@@ -12,6 +13,7 @@ use std::fs::File;
 use lalrpop_util;
 use queue;
 use unicode_segmentation::UnicodeSegmentation;
+use unicode_normalization::UnicodeNormalization;
 use ast::*;
 use parser;
 
@@ -19,8 +21,19 @@ macro_rules! s {
     ($e:expr) => (String::from($e));
 }
 macro_rules! prim {
+    ($e:expr) => ({
+        let f = $e;
+        Value::PrimFunc(Arc::new(Box::new(move |args: Vec<Value>| Ok(f(args)))))
+    });
+}
+// For builtins that return a Result instead of panicking on bad arguments
+macro_rules! fallible_prim {
     ($e:expr) => (Value::PrimFunc(Arc::new(Box::new($e))));
 }
+// For builtins that pull from or push to the pipes they are called in
+macro_rules! pipe_prim {
+    ($e:expr) => (Value::PipeFunc(Arc::new(Box::new($e))));
+}
 
 pub fn box_to_usize(b: Box<Value>) -> usize {
     Box::into_raw(b) as usize
@@ -32,9 +45,226 @@ pub fn box_from_usize(p: usize) -> Box<Value> {
     }
 }
 
+/// The number of values a pipe can hold before `push` blocks, unless changed
+/// with `set_pipe_buffer_size`.
+pub const DEFAULT_PIPE_BUFFER_SIZE: usize = 1;
+static PIPE_BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_PIPE_BUFFER_SIZE);
+
+pub fn pipe_buffer_size() -> usize {
+    PIPE_BUFFER_SIZE.load(Ordering::SeqCst)
+}
+
+/// Sets the buffer size used for every pipe created afterwards.
+/// A pipe always holds at least one value, so 0 is treated as 1.
+pub fn set_pipe_buffer_size(size: usize) {
+    PIPE_BUFFER_SIZE.store(clamp_pipe_buffer_size(size), Ordering::SeqCst);
+}
+
+fn clamp_pipe_buffer_size(size: usize) -> usize {
+    ::std::cmp::max(size, 1)
+}
+
+/// The buffer size that `NEMO_PIPE_BUFFER` is set to, or `None` if `value`
+/// isn't a positive whole number.
+pub fn parse_pipe_buffer_size(value: &str) -> Option<usize> {
+    match value.parse::<usize>() {
+        Ok(n) if n >= 1 => Some(n),
+        _ => None,
+    }
+}
+
+/// How deeply function calls can nest before a call gives a RecursionLimit
+/// error, unless changed with `set_max_call_depth`.
+/// With it the main interpreter thread's stack is about 64 MB. A deeper default
+/// would make every run reserve a stack of hundreds of MB, and programs that
+/// need deeper recursion can raise the limit.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+static MAX_CALL_DEPTH: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_CALL_DEPTH);
+// The stack set aside for each call. This is a measured rule of thumb, not a
+// bound: in an unoptimized build a call like the one in `1 + count(n - 1)`
+// takes about 33 KiB, and each further expression the call is nested in adds
+// about 16 KiB. Optimized builds need a tenth of that. A function whose
+// recursive call is nested several expressions deep can still overflow the
+// stack before it reaches the limit in an unoptimized build.
+const STACK_PER_CALL: usize = 64 * 1024;
+
+/// The largest call depth limit that can be set. A stack for it is already
+/// about 6 GB of address space.
+pub const MAX_CALL_DEPTH_LIMIT: usize = 100000;
+/// The stack size of the threads that run pipe stages. It is far smaller than
+/// the main interpreter thread's, so in a pipe stage calls nest at most 496
+/// deep, even when the call depth limit is higher.
+pub const PIPE_STAGE_STACK_SIZE: usize = 32 * 1024 * 1024;
+/// The stack size of the thread that a `Sandbox` runs code on. Calls nest less
+/// deeply in a sandbox too.
+pub const SANDBOX_STACK_SIZE: usize = 16 * 1024 * 1024;
+
+pub fn max_call_depth() -> usize {
+    MAX_CALL_DEPTH.load(Ordering::SeqCst)
+}
+
+/// Sets the call depth limit, which is kept from 1 to `MAX_CALL_DEPTH_LIMIT`.
+/// It only works if the thread that runs nemo code has a stack of
+/// `interpreter_stack_size()`, so it should be set before the interpreter starts.
+/// Pipe stages and sandboxes have smaller stacks, so their limit is lower
+/// whenever this one is higher than fits in them.
+pub fn set_max_call_depth(depth: usize) {
+    MAX_CALL_DEPTH.store(::std::cmp::min(::std::cmp::max(depth, 1), MAX_CALL_DEPTH_LIMIT), Ordering::SeqCst);
+}
+
+/// The stack size a thread needs to run nemo code that goes as deep as the
+/// call depth limit, instead of overflowing the stack first.
+pub fn interpreter_stack_size() -> usize {
+    max_call_depth().saturating_add(16).saturating_mul(STACK_PER_CALL)
+}
+
+thread_local! {
+    // How many function calls are running on this thread right now
+    static CALL_DEPTH: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0);
+    // A lower limit for threads with smaller stacks, like pipe stages
+    static THREAD_CALL_LIMIT: ::std::cell::Cell<Option<usize>> = ::std::cell::Cell::new(None);
+}
+
+// How deeply calls can nest on a thread with a stack of `stack_size`
+fn calls_that_fit(stack_size: usize) -> usize {
+    stack_size / STACK_PER_CALL - 16
+}
+
+// Starts a thread with a stack of `stack_size`, and limits its calls to
+// what fits in that stack.
+fn spawn_with_stack<T, F>(stack_size: usize, f: F) -> io::Result<thread::JoinHandle<T>>
+    where T: Send + 'static, F: FnOnce() -> T + Send + 'static {
+    thread::Builder::new().stack_size(stack_size).spawn(move || {
+        THREAD_CALL_LIMIT.with(|limit| limit.set(Some(calls_that_fit(stack_size))));
+        f()
+    })
+}
+
+fn spawn_pipe_stage<F: FnOnce() + Send + 'static>(f: F) {
+    spawn_with_stack(PIPE_STAGE_STACK_SIZE, f).unwrap();
+}
+
+// Counts a call for as long as it is running
+struct CallDepthGuard;
+
+impl CallDepthGuard {
+    fn enter<'a>() -> Result<CallDepthGuard, Error<'a>> {
+        let limit = match THREAD_CALL_LIMIT.with(|limit| limit.get()) {
+            Some(thread_limit) => ::std::cmp::min(thread_limit, max_call_depth()),
+            None => max_call_depth(),
+        };
+        CALL_DEPTH.with(|depth| {
+            if depth.get() >= limit {
+                Err(Error::RecursionLimit(limit))
+            } else {
+                depth.set(depth.get() + 1);
+                Ok(CallDepthGuard)
+            }
+        })
+    }
+}
+
+impl Drop for CallDepthGuard {
+    fn drop(&mut self) {
+        CALL_DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// The ends of a pipe, shared by the code that pushes to or pulls from it.
+pub type PipeProducer = Arc<Mutex<queue::Producer<Value>>>;
+pub type PipeConsumer = Arc<Mutex<queue::Consumer<Value>>>;
+
+pub fn make_pipe(capacity: usize) -> (PipeProducer, PipeConsumer) {
+    let (send, recv) = queue::make(capacity);
+    (Arc::new(Mutex::new(send)), Arc::new(Mutex::new(recv)))
+}
+
+static DETACHED_INIT: Once = Once::new();
+static DETACHED_PRODUCER: AtomicUsize = AtomicUsize::new(0);
+static DETACHED_CONSUMER: AtomicUsize = AtomicUsize::new(0);
+
+fn init_detached_pipes() {
+    DETACHED_INIT.call_once(|| {
+        // Both pipes live for the rest of the program, and their other ends are never used.
+        let (producer, consumer) = make_pipe(1);
+        ::std::mem::forget(consumer);
+        DETACHED_PRODUCER.store(Box::into_raw(Box::new(producer)) as usize, Ordering::SeqCst);
+        let (producer, consumer) = make_pipe(1);
+        ::std::mem::forget(producer);
+        DETACHED_CONSUMER.store(Box::into_raw(Box::new(consumer)) as usize, Ordering::SeqCst);
+    });
+}
+
+/// The producer to give code that runs outside of any pipeline. Nothing ever
+/// reads from it, so pushing to it is an `Error::PushedToNone` instead of
+/// blocking forever once the pipe is full.
+pub fn detached_producer() -> PipeProducer {
+    init_detached_pipes();
+    let producer = DETACHED_PRODUCER.load(Ordering::SeqCst) as *const Arc<Mutex<queue::Producer<Value>>>;
+    unsafe { (*producer).clone() }
+}
+
+/// The consumer to give code that runs outside of any pipeline. Nothing ever
+/// writes to it, so pulling from it gives `FinishedPipe` right away.
+pub fn detached_consumer() -> PipeConsumer {
+    init_detached_pipes();
+    let consumer = DETACHED_CONSUMER.load(Ordering::SeqCst) as *const Arc<Mutex<queue::Consumer<Value>>>;
+    unsafe { (*consumer).clone() }
+}
+
+fn pull(this: &Arc<Mutex<queue::Consumer<Value>>>) -> Value {
+    if Arc::ptr_eq(this, &detached_consumer()) {
+        return Value::FinishedPipe;
+    }
+    this.lock().unwrap().pop()
+}
+
+fn push_to<'a>(next: &Arc<Mutex<queue::Producer<Value>>>, v: Value) -> Result<(), Error<'a>> {
+    if Arc::ptr_eq(next, &detached_producer()) {
+        return Err(Error::PushedToNone);
+    }
+    next.lock().unwrap().push(v);
+    Ok(())
+}
+
+/// Pushes every value in `values` in order, locking the pipe once for all of
+/// them instead of once each. Blocks whenever the pipe is full, like `push`.
+pub fn push_all<'a>(next: &Arc<Mutex<queue::Producer<Value>>>, values: Vec<Value>) -> Result<(), Error<'a>> {
+    if values.is_empty() {
+        return Ok(());
+    }
+    if Arc::ptr_eq(next, &detached_producer()) {
+        return Err(Error::PushedToNone);
+    }
+    let producer = next.lock().unwrap();
+    for v in values {
+        producer.push(v);
+    }
+    Ok(())
+}
+
+/// Pulls every value that is already waiting in the pipe without blocking,
+/// locking it once. Stops after a `FinishedPipe`, because a finished pipe
+/// keeps giving them.
+pub fn pull_available(this: &Arc<Mutex<queue::Consumer<Value>>>) -> Vec<Value> {
+    if Arc::ptr_eq(this, &detached_consumer()) {
+        return vec![Value::FinishedPipe];
+    }
+    let consumer = this.lock().unwrap();
+    let mut values = vec![];
+    while let Some(v) = consumer.try_pop() {
+        let finished = v == Value::FinishedPipe;
+        values.push(v);
+        if finished {
+            break;
+        }
+    }
+    values
+}
+
 #[derive(Debug, Clone)]
 pub enum Error<'a> {
-    ParseError(lalrpop_util::ParseError<usize, (usize, &'a str), ()>),
+    ParseError(lalrpop_util::ParseError<usize, (usize, &'a str), &'static str>),
     InvalidTypes(String),
     Unimplemented(String),
     UndefinedName(String),
@@ -43,16 +273,44 @@ pub enum Error<'a> {
     // Not really an error, but treating early returns as one
     // is the easiest way to implement them.
     EarlyReturn(Value),
+    // break and continue unwind to the nearest loop the same way.
+    LoopBreak,
+    LoopContinue,
+    LoopControlOutsideLoop(String),
+    // A range literal that isn't in a pipeline, so has nowhere to push
+    RangeOutsidePipe(String),
     OutOfBoundIndex(String),
     UndefinedAttribute(String),
+    ArityMismatch { name: String, expected: usize, got: usize },
+    // For functions with optional arguments, which take from min to max
+    ArityOutOfRange { name: String, min: usize, max: usize, got: usize },
+    NoMatchingPattern(String),
+    AssertionFailed(String),
+    IoError(String),
+    SyntaxError(String),
+    // A named argument that doesn't match a parameter, or a parameter that is
+    // given twice or not at all
+    InvalidArgument(String),
+    // Calls nested more deeply than the limit, which is given
+    RecursionLimit(usize),
+    // Code in a sandbox panicked, with the panic's message
+    Panicked(String),
 }
 
+type PrimFn = Fn(Vec<Value>) -> Result<Value, Error<'static>>;
+type PipeFn = Fn(Vec<Value>, PipeConsumer, PipeProducer) -> Result<Value, Error<'static>>;
+
 #[derive(Clone)]
 pub enum Value {
     Number(f64),
+    Int(i64),
     Str(String),
-    PrimFunc(Arc<Box<Fn(Vec<Value>) -> Value>>),
-    UserFunc(Definition, ProtectedEnv),
+    // A builtin that only needs its arguments.
+    PrimFunc(Arc<Box<PrimFn>>),
+    // A builtin that also gets the pipes it is called in, like map.
+    PipeFunc(Arc<Box<PipeFn>>),
+    // The definition is shared, so copying a function doesn't copy its body.
+    UserFunc(Arc<Definition>, ProtectedEnv),
     FinishedPipe,
     Bool(bool),
     Module(ProtectedEnv),
@@ -64,8 +322,9 @@ impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Value::Number(n) =>  write!(f, "{}", n),
+            Value::Int(n) =>  write!(f, "{}", n),
             Value::Str(ref s) =>  write!(f, "'{}'", s),
-            Value::PrimFunc(_) => write!(f, "Primative {{...}}"),
+            Value::PrimFunc(_) | Value::PipeFunc(_) => write!(f, "Primative {{...}}"),
             Value::UserFunc(ref def, _) => {
                 write!(f, "function {}(", def.prototype.name);
                 if def.prototype.args.len() >= 1 {
@@ -87,8 +346,9 @@ impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Value::Number(n) =>  write!(f, "{}", n),
+            Value::Int(n) =>  write!(f, "{}", n),
             Value::Str(ref s) =>  write!(f, "{}", s),
-            Value::PrimFunc(_) => write!(f, "Primative {{...}}"),
+            Value::PrimFunc(_) | Value::PipeFunc(_) => write!(f, "Primative {{...}}"),
             Value::UserFunc(ref def, _) => {
                 write!(f, "function {}(", def.prototype.name);
                 if def.prototype.args.len() >= 1 {
@@ -111,10 +371,20 @@ impl PartialEq for Value {
     fn eq(&self, other: &Value) -> bool {
         match (self, other) {
             (&Value::Number(n1), &Value::Number(n2)) => n1 == n2,
+            (&Value::Int(n1), &Value::Int(n2)) => n1 == n2,
+            (&Value::Int(n1), &Value::Number(n2)) => n1 as f64 == n2,
+            (&Value::Number(n1), &Value::Int(n2)) => n1 == n2 as f64,
             (&Value::Str(ref s1), &Value::Str(ref s2)) => s1 == s2,
             (&Value::FinishedPipe, &Value::FinishedPipe) => true,
             (&Value::Bool(b1), &Value::Bool(b2)) => b1 == b2,
-            (x1, x2) => (x1 as *const Value as usize) == (x2 as *const Value as usize),
+            // Builtins and modules are equal only to themselves, and a clone of
+            // one is still the same one. User functions are equal when they have
+            // the same code and were made in the same environment.
+            (&Value::PrimFunc(ref f1), &Value::PrimFunc(ref f2)) => Arc::ptr_eq(f1, f2),
+            (&Value::PipeFunc(ref f1), &Value::PipeFunc(ref f2)) => Arc::ptr_eq(f1, f2),
+            (&Value::UserFunc(ref d1, ref e1), &Value::UserFunc(ref d2, ref e2)) => d1 == d2 && Arc::ptr_eq(e1, e2),
+            (&Value::Module(ref m1), &Value::Module(ref m2)) => Arc::ptr_eq(m1, m2),
+            _ => false,
         }
     }
 }
@@ -124,199 +394,1316 @@ impl Value {
         // A Value is truthy if it is not Bool(false)
         self.ne(&Value::Bool(false))
     }
+    /// The value as a float, if it is an Int or a Number.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Number(n) => Some(n),
+            Value::Int(n) => Some(n as f64),
+            _ => None,
+        }
+    }
+}
+
+/// The most graphemes of a value's `Debug` output that an error message will include.
+pub const MAX_ERROR_VALUE_LEN: usize = 64;
+
+/// Renders a value for use in an error message. Anything longer than
+/// `MAX_ERROR_VALUE_LEN` graphemes is cut short and ends in `...`, so an error
+/// never has to print a huge value in full.
+pub fn display_for_error(v: &Value) -> String {
+    let repr = format!("{:?}", v);
+    let graphemes: Vec<&str> = UnicodeSegmentation::graphemes(repr.as_str(), true).collect();
+    if graphemes.len() <= MAX_ERROR_VALUE_LEN {
+        repr
+    } else {
+        format!("{}...", graphemes[..MAX_ERROR_VALUE_LEN].concat())
+    }
+}
+
+/// Renders a value the way the REPL shows results. When `always_show_float`
+/// is set, whole floats keep their decimal point, so `2.0` is shown as `2.0`
+/// rather than `2`. Ints are never given one.
+pub fn display_for_repl(v: &Value, always_show_float: bool) -> String {
+    match *v {
+        Value::Number(n) if always_show_float && n.is_finite() && n.fract() == 0.0 => format!("{:.1}", n),
+        _ => format!("{:?}", v),
+    }
+}
+
+/// Splits a trailing `;` off a line typed at the REPL. The REPL evaluates the
+/// rest of the line, and only prints the result if there was no `;`.
+pub fn strip_repl_semicolon(input: &str) -> (&str, bool) {
+    let trimmed = input.trim_right();
+    if trimmed.ends_with(';') {
+        (&trimmed[..trimmed.len() - 1], true)
+    } else {
+        (input, false)
+    }
+}
+
+/// Reads a REPL setting like `NEMO_PROMPT` from the environment, falling back
+/// to `default` when it isn't set.
+pub fn repl_setting(var: &str, default: &str) -> String {
+    match ::std::env::var(var) {
+        Ok(val) => val,
+        Err(_) => default.to_owned(),
+    }
+}
+
+/// Formats a number in one of `format_number`'s styles: `fixed` and `grouped`
+/// round to `places` decimal places, and `sci` uses scientific notation with
+/// `places` digits after the point. Returns None for an unknown style.
+pub fn format_number(n: f64, style: &str, places: usize) -> Option<String> {
+    match style {
+        "fixed" => Some(format!("{:.*}", places, n)),
+        "sci" => Some(format!("{:.*e}", places, n)),
+        "grouped" => {
+            let fixed = format!("{:.*}", places, n.abs());
+            let (int_part, frac_part) = match fixed.find('.') {
+                Some(i) => fixed.split_at(i),
+                None => (fixed.as_str(), ""),
+            };
+            let mut grouped = String::new();
+            for (i, c) in int_part.chars().enumerate() {
+                if i > 0 && (int_part.len() - i) % 3 == 0 {
+                    grouped.push(',');
+                }
+                grouped.push(c);
+            }
+            let sign = if n < 0.0 && fixed.chars().any(|c| c != '0' && c != '.') { "-" } else { "" };
+            Some(format!("{}{}{}", sign, grouped, frac_part))
+        }
+        _ => None,
+    }
+}
+
+/// The most decimal places `format_number` will show, which is already far
+/// more than an f64 can hold.
+pub const MAX_FORMAT_PLACES: usize = 100;
+
+/// Rounds `n` to `decimals` decimal places, padding with zeros, for
+/// `format_number(n, decimals)`. A negative `decimals` rounds to the left of
+/// the point instead, so -2 rounds to the nearest hundred. Halves round away
+/// from zero.
+pub fn round_to_decimals(n: f64, decimals: i64) -> String {
+    if decimals >= 0 {
+        let decimals = ::std::cmp::min(decimals, MAX_FORMAT_PLACES as i64);
+        let scale = 10f64.powi(decimals as i32);
+        let rounded = if scale.is_finite() && (n * scale).is_finite() { (n * scale).round() / scale } else { n };
+        format!("{:.*}", decimals as usize, rounded)
+    } else {
+        // Past 10^308 the scale is infinite, and every finite number rounds to 0
+        let scale = 10f64.powi(::std::cmp::min(decimals.checked_neg().unwrap_or(i64::max_value()), 400) as i32);
+        if !scale.is_finite() && n.is_finite() {
+            return s!("0");
+        }
+        let rounded = (n / scale).round() * scale;
+        // Keeps -0.0 from showing a sign
+        format!("{:.0}", if rounded == 0.0 { 0.0 } else { rounded })
+    }
 }
 
-// The format and operations of the Enviroment are inspired by SICP's scheme interpreter.
+// The format and operations of the Environment are inspired by SICP's scheme interpreter.
 // https://mitpress.mit.edu/sicp/full-text/book/book-Z-H-26.html
 #[derive(Debug, Clone)]
-pub struct Enviroment {
+pub struct Environment {
     current_frame: HashMap<String, Option<Value>>,
-    prev: Box<Option<ProtectedEnv>>,
+    prev: Option<ProtectedEnv>,
 }
-unsafe impl Send for Enviroment{}
+unsafe impl Send for Environment{}
 
-impl Enviroment {
-    pub fn new() -> Enviroment {
-        Enviroment {
+impl Environment {
+    pub fn new() -> Environment {
+        Environment {
             current_frame: HashMap::new(),
-            prev: Box::new(None),
+            prev: None,
         }
     }
-    pub fn extend(bindings: Vec<(String, Value)>, prev: Option<ProtectedEnv>) -> Enviroment {
-        let mut frame = HashMap::new();
+    pub fn extend(bindings: Vec<(String, Value)>, prev: Option<ProtectedEnv>) -> Environment {
+        let mut frame = HashMap::with_capacity(bindings.len());
         for (key, val) in bindings {
             frame.insert(key, Some(val));
         }
-        Enviroment {
+        Environment {
             current_frame: frame,
-            prev: Box::new(prev),
+            prev,
         }
     }
     pub fn lookup(&self, name: &str) -> Option<Option<Value>> {
-        let val = self.current_frame.get(&String::from(name));
-        if val.is_some() {
-            val.cloned()
-        } else {
-            if let Some(ref prev) = *self.prev {
-                let lock = prev.lock().unwrap();
-                return lock.borrow().lookup(name);
-            } else {
-                None
-            }
+        if let Some(val) = self.current_frame.get(name) {
+            return Some(val.clone());
+        }
+        // Walks up the frames one at a time, only ever holding one lock, so a
+        // deep chain of frames can't overflow the stack.
+        let mut frame = match self.prev {
+            Some(ref prev) => prev.clone(),
+            None => return None,
+        };
+        loop {
+            let prev = {
+                let lock = frame.lock().unwrap();
+                let env = lock.borrow();
+                if let Some(val) = env.current_frame.get(name) {
+                    return Some(val.clone());
+                }
+                match env.prev {
+                    Some(ref prev) => prev.clone(),
+                    None => return None,
+                }
+            };
+            frame = prev;
         }
     }
     pub fn set(&mut self, name: String, val: Option<Value>) {
         self.current_frame.insert(name, val);
     }
+    /// The number of frames from this one up to and including the global frame.
+    pub fn depth(&self) -> usize {
+        // Walks up the frames the same way as lookup
+        let mut depth = 1;
+        let mut next = self.prev.clone();
+        while let Some(frame) = next {
+            let lock = frame.lock().unwrap();
+            let env = lock.borrow();
+            depth += 1;
+            next = env.prev.clone();
+        }
+        depth
+    }
+    /// Every name that can be looked up from this frame, in sorted order.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.current_frame.keys().cloned().collect();
+        let mut next = self.prev.clone();
+        while let Some(frame) = next {
+            let lock = frame.lock().unwrap();
+            let env = lock.borrow();
+            names.extend(env.current_frame.keys().cloned());
+            next = env.prev.clone();
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+    /// The names bound in this frame only, in sorted order.
+    pub fn frame_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.current_frame.keys().cloned().collect();
+        names.sort();
+        names
+    }
+    /// The names bound in this frame along with their values, sorted by name.
+    /// Names that are declared but don't have a value yet are left out.
+    pub fn bindings(&self) -> Vec<(String, Value)> {
+        let mut bindings: Vec<(String, Value)> = self.current_frame.iter()
+            .filter_map(|(name, val)| val.clone().map(|val| (name.clone(), val)))
+            .collect();
+        bindings.sort_by(|a, b| a.0.cmp(&b.0));
+        bindings
+    }
+    /// Assigns to `name` the way `:=` does. If a frame between this one and the
+    /// global frame already binds `name`, that binding is updated, so closures
+    /// share the variables they capture. Otherwise `name` becomes a new
+    /// variable in this frame. Names in the global frame are never rebound from
+    /// inside a function.
+    pub fn assign(&mut self, name: String, val: Value) {
+        if !self.current_frame.contains_key(&name) {
+            if let Some(ref prev) = self.prev {
+                if Environment::rebind(prev.clone(), &name, &val) {
+                    return;
+                }
+            }
+        }
+        self.set(name, Some(val));
+    }
+    // Updates `name` in the nearest frame from `frame` up that binds it,
+    // stopping before the global frame. Like lookup, it holds one lock at a time.
+    fn rebind(mut frame: ProtectedEnv, name: &str, val: &Value) -> bool {
+        loop {
+            let prev = {
+                let lock = frame.lock().unwrap();
+                let mut env = lock.borrow_mut();
+                let prev = match env.prev {
+                    Some(ref prev) => prev.clone(),
+                    // the global frame
+                    None => return false,
+                };
+                if env.current_frame.contains_key(name) {
+                    env.set(name.to_owned(), Some(val.clone()));
+                    return true;
+                }
+                prev
+            };
+            frame = prev;
+        }
+    }
+}
+
+impl Drop for Environment {
+    fn drop(&mut self) {
+        // Dropping a long chain of frames would recurse once per frame,
+        // so the frames nothing else refers to are unlinked in a loop.
+        let mut prev = self.prev.take();
+        while let Some(frame) = prev {
+            prev = match Arc::try_unwrap(frame) {
+                // A poisoned frame is still unlinked, since panicking in drop would abort
+                Ok(frame) => frame.into_inner().unwrap_or_else(|e| e.into_inner()).into_inner().prev.take(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+#[deprecated(note = "renamed to Environment")]
+pub type Enviroment = Environment;
+
+type ProtectedEnv = Arc<Mutex<RefCell<Environment>>>;
+
+/// A summary of the names defined in an environment, for the REPL's `:stats` command.
+#[derive(Debug, PartialEq)]
+pub struct EnvStats {
+    pub names: usize,
+    pub functions: usize,
+    pub values: usize,
+    pub depth: usize,
+}
+
+impl fmt::Display for EnvStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} names defined ({} functions, {} values), {} frames deep",
+               self.names, self.functions, self.values, self.depth)
+    }
 }
 
-type ProtectedEnv = Arc<Mutex<RefCell<Enviroment>>>;
+/// Counts the names in `env` that are not part of the initial environment.
+pub fn env_stats(env: &ProtectedEnv) -> EnvStats {
+    let builtins = {
+        let initial = initial_environment();
+        let lock = initial.lock().unwrap();
+        let names = lock.borrow().names();
+        names
+    };
+    let lock = env.lock().unwrap();
+    let env = lock.borrow();
+    let mut stats = EnvStats { names: 0, functions: 0, values: 0, depth: env.depth() };
+    for name in env.names() {
+        if builtins.binary_search(&name).is_ok() {
+            continue;
+        }
+        match env.lookup(&name) {
+            Some(Some(Value::UserFunc(..))) | Some(Some(Value::PrimFunc(_))) | Some(Some(Value::PipeFunc(_))) => stats.functions += 1,
+            Some(Some(_)) => stats.values += 1,
+            _ => continue,
+        }
+        stats.names += 1;
+    }
+    stats
+}
 
 pub fn define_function(def: Definition, env: ProtectedEnv) {
     let name = def.prototype.name.clone();
-    let func = Value::UserFunc(def, env.clone());
+    let func = Value::UserFunc(Arc::new(def), env.clone());
     let lock = env.lock().unwrap();
     lock.borrow_mut().set(name, Some(func));
 }
 
-pub fn load_module_into_env<'a>(module: &'a str, env: ProtectedEnv, dir: &str) -> Result<(), lalrpop_util::ParseError<usize, (usize, &'a str), ()>> {
+#[derive(Debug)]
+pub enum LoadError<'a> {
+    ParseError(lalrpop_util::ParseError<usize, (usize, &'a str), &'static str>),
+    // The path as it was written, and why it couldn't be read
+    IoError(String, io::Error),
+    // The path of a module that ends up using itself
+    CircularImport(String),
+    // A syntax error in a used module, placed in that module's file
+    ModuleSyntaxError(Diagnostic),
+}
+
+impl<'a> fmt::Display for LoadError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::ParseError(ref e) => write!(f, "syntax error: {:?}", e),
+            LoadError::IoError(ref path, ref e) => write!(f, "could not open module '{}': {}", path, e),
+            LoadError::CircularImport(ref path) => write!(f, "module '{}' ends up using itself", path),
+            LoadError::ModuleSyntaxError(ref d) => match (d.line, d.column) {
+                (Some(line), Some(column)) => write!(f, "syntax error in module '{}' at line {}, column {}: {}", d.file, line, column, d.message),
+                _ => write!(f, "syntax error in module '{}': {}", d.file, d.message),
+            },
+        }
+    }
+}
+
+impl<'a> From<lalrpop_util::ParseError<usize, (usize, &'a str), &'static str>> for LoadError<'a> {
+    fn from(e: lalrpop_util::ParseError<usize, (usize, &'a str), &'static str>) -> LoadError<'a> {
+        LoadError::ParseError(e)
+    }
+}
+
+/// An error in the form that `--diagnostics json` reports it. Lines and
+/// columns start at 1, and are None for errors that don't have a place in the
+/// source, like runtime errors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub severity: &'static str,
+    pub message: String,
+}
+
+/// The line and column of the byte `offset` in `src`, both starting at 1.
+/// Columns count chars, not bytes.
+pub fn line_and_column(src: &str, offset: usize) -> (usize, usize) {
+    let before = &src[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// Describes a syntax error in `src`, the contents of `file`.
+pub fn parse_error_diagnostic(file: &str, src: &str, e: &lalrpop_util::ParseError<usize, (usize, &str), &'static str>) -> Diagnostic {
+    use lalrpop_util::ParseError;
+    let (offset, message) = match *e {
+        ParseError::InvalidToken { location } => (Some(location), s!("invalid token")),
+        ParseError::UnrecognizedToken { token: Some((start, (_, token), _)), .. } => (Some(start), format!("unexpected '{}'", token)),
+        ParseError::UnrecognizedToken { token: None, .. } => (Some(src.len()), s!("unexpected end of file")),
+        ParseError::ExtraToken { token: (start, (_, token), _) } => (Some(start), format!("unexpected '{}'", token)),
+        ParseError::User { error } => (None, error.to_owned()),
+    };
+    let place = offset.map(|offset| line_and_column(src, offset));
+    Diagnostic {
+        file: file.to_owned(),
+        line: place.map(|(line, _)| line),
+        column: place.map(|(_, column)| column),
+        severity: "error",
+        message,
+    }
+}
+
+/// Describes an error that has no place in the source, like a runtime error.
+pub fn diagnostic_without_place(file: &str, message: String) -> Diagnostic {
+    Diagnostic {
+        file: file.to_owned(),
+        line: None,
+        column: None,
+        severity: "error",
+        message,
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_number(n: Option<usize>) -> String {
+    match n {
+        Some(n) => n.to_string(),
+        None => s!("null"),
+    }
+}
+
+/// Renders diagnostics as a JSON array of
+/// `{"file", "line", "column", "severity", "message"}` objects.
+pub fn diagnostics_to_json(diagnostics: &[Diagnostic]) -> String {
+    let objects: Vec<String> = diagnostics.iter().map(|d| {
+        format!("{{\"file\":{},\"line\":{},\"column\":{},\"severity\":{},\"message\":{}}}",
+                json_string(&d.file), json_number(d.line), json_number(d.column),
+                json_string(d.severity), json_string(&d.message))
+    }).collect();
+    format!("[{}]", objects.join(","))
+}
+
+/// Reads the source of the module at `path`, naming the module `name` in any error.
+fn read_module(path: &str, name: &str) -> Result<String, LoadError<'static>> {
+    let mut contents = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+        Ok(_) => Ok(contents),
+        Err(e) => Err(LoadError::IoError(name.to_owned(), e)),
+    }
+}
+
+/// The name a `use` binds its module to: the name after `as` if there is
+/// one, or else the module's file name without its extension.
+pub fn module_name(module_path: &str, alias: Option<String>) -> String {
+    match alias {
+        Some(alias) => alias,
+        None => ::std::path::Path::new(module_path).file_stem().unwrap().to_str().unwrap().to_owned(),
+    }
+}
+
+pub fn load_module_into_env<'a>(module: &'a str, env: ProtectedEnv, dir: &str) -> Result<(), LoadError<'a>> {
     let tops = parser::parse_Program(module)?;
     for top in tops {
         match top {
             Top::Definition(def) => define_function(def, env.clone()),
-            Top::Use(module_path) => {
-                let path = if ::std::path::Path::new(&module_path).is_absolute() {
-                    module_path.clone()
-                } else {
-                    let p = ::std::path::Path::new(dir).join(&module_path);
-                    let b = p.as_path().clone();
-                    b.to_str().unwrap().to_owned()
-                };
-                let mut file = File::open(&path).unwrap();
-                let mut contents = String::new();
-                file.read_to_string(&mut contents).unwrap();
-                let module_env = initial_enviroment();
-                match load_module_into_env(&contents, module_env.clone(), ::std::path::Path::new(&path).parent().unwrap_or(::std::path::Path::new("/")).to_str().unwrap()) {
-                    Ok(_) => {},
-                    Err(e) => println!("Syntax error in module {:?}: {:?}", module_path, e),
-                };
-                let name = ::std::path::Path::new(&module_path).file_stem().unwrap().to_str().unwrap().to_owned();
-                let lock = env.lock().unwrap();
-                lock.borrow_mut().set(name, Some(Value::Module(module_env)));
-            }
+            Top::Use(module_path, alias) => use_module(&module_path, alias, env.clone(), dir)?,
         }
     }
     Ok(())
 }
 
+thread_local! {
+    // The canonical paths of the modules that are part way through loading,
+    // innermost last, so that a module that uses itself can be caught.
+    static MODULES_LOADING: RefCell<Vec<::std::path::PathBuf>> = RefCell::new(vec![]);
+}
+
+type ModuleCache = Mutex<HashMap<::std::path::PathBuf, ProtectedEnv>>;
+
+static MODULE_CACHE_INIT: Once = Once::new();
+static MODULE_CACHE: AtomicUsize = AtomicUsize::new(0);
+static MODULE_LOAD_LOCK: AtomicUsize = AtomicUsize::new(0);
+
+// Every module that has been loaded, by canonical path. Modules are
+// singletons: every use of a file, on any thread, shares one module, and
+// the file is only ever loaded once. A module that fails to load isn't
+// cached, so the next use of it tries again.
+fn module_cache() -> &'static ModuleCache {
+    init_module_cache();
+    unsafe { &*(MODULE_CACHE.load(Ordering::SeqCst) as *const ModuleCache) }
+}
+
+// Held for the whole of a use that isn't inside another module's loading, so
+// two threads can't both load the same module.
+fn module_load_lock() -> &'static Mutex<()> {
+    init_module_cache();
+    unsafe { &*(MODULE_LOAD_LOCK.load(Ordering::SeqCst) as *const Mutex<()>) }
+}
+
+fn init_module_cache() {
+    MODULE_CACHE_INIT.call_once(|| {
+        // Both live for the rest of the program.
+        let cache: Box<ModuleCache> = Box::new(Mutex::new(HashMap::new()));
+        MODULE_CACHE.store(Box::into_raw(cache) as usize, Ordering::SeqCst);
+        MODULE_LOAD_LOCK.store(Box::into_raw(Box::new(Mutex::new(()))) as usize, Ordering::SeqCst);
+    });
+}
+
+/// Loads the module at `module_path` into `env` the way `use` does. A relative
+/// path is found from `dir`, the directory of the file doing the importing, and
+/// the module's own `use`s are found from the module's directory.
+pub fn use_module(module_path: &str, alias: Option<String>, env: ProtectedEnv, dir: &str) -> Result<(), LoadError<'static>> {
+    let path = ::std::path::Path::new(dir).join(module_path);
+    let path = path.to_str().unwrap();
+    let canonical = match ::std::fs::canonicalize(path) {
+        Ok(canonical) => canonical,
+        Err(e) => return Err(LoadError::IoError(module_path.to_owned(), e)),
+    };
+    // A use inside a module that is loading already holds the lock
+    let _loading = if MODULES_LOADING.with(|loading| loading.borrow().is_empty()) {
+        Some(module_load_lock().lock().unwrap_or_else(|e| e.into_inner()))
+    } else {
+        None
+    };
+    let cached = module_cache().lock().unwrap().get(&canonical).cloned();
+    let module_env = match cached {
+        Some(module_env) => module_env,
+        None => {
+            if MODULES_LOADING.with(|loading| loading.borrow().contains(&canonical)) {
+                return Err(LoadError::CircularImport(module_path.to_owned()));
+            }
+            let contents = read_module(path, module_path)?;
+            let module_env = initial_environment();
+            let module_dir = ::std::path::Path::new(path).parent().unwrap_or(::std::path::Path::new("/")).to_str().unwrap().to_owned();
+            MODULES_LOADING.with(|loading| loading.borrow_mut().push(canonical.clone()));
+            let result = load_module_into_env(&contents, module_env.clone(), &module_dir);
+            MODULES_LOADING.with(|loading| loading.borrow_mut().pop());
+            // A module that didn't load isn't cached or bound, so nothing can
+            // use the definitions that parsed before the error.
+            match result {
+                Ok(_) => {
+                    module_cache().lock().unwrap().insert(canonical, module_env.clone());
+                },
+                Err(LoadError::ParseError(ref e)) => return Err(LoadError::ModuleSyntaxError(parse_error_diagnostic(path, &contents, e))),
+                Err(LoadError::IoError(path, e)) => return Err(LoadError::IoError(path, e)),
+                Err(LoadError::CircularImport(path)) => return Err(LoadError::CircularImport(path)),
+                Err(LoadError::ModuleSyntaxError(d)) => return Err(LoadError::ModuleSyntaxError(d)),
+            };
+            module_env
+        }
+    };
+    let lock = env.lock().unwrap();
+    lock.borrow_mut().set(module_name(module_path, alias), Some(Value::Module(module_env)));
+    Ok(())
+}
+
+type ReadLine = FnMut(&mut String) -> io::Result<usize> + Send;
+
+/// Where `print`, `println` and `write` send their output, where `inspect`
+/// shows values, and where `input` reads from. Tests can use buffers instead
+/// of stdin, stdout and stderr.
+#[derive(Clone)]
+pub struct Io {
+    read_line: Arc<Mutex<Box<ReadLine>>>,
+    output: Arc<Mutex<Box<Write + Send>>>,
+    errors: Arc<Mutex<Box<Write + Send>>>,
+}
+
+impl Io {
+    pub fn new<R, W, E>(mut input: R, output: W, errors: E) -> Io
+        where R: BufRead + Send + 'static, W: Write + Send + 'static, E: Write + Send + 'static {
+        Io {
+            read_line: Arc::new(Mutex::new(Box::new(move |line: &mut String| input.read_line(line)))),
+            output: Arc::new(Mutex::new(Box::new(output))),
+            errors: Arc::new(Mutex::new(Box::new(errors))),
+        }
+    }
+    pub fn stdio() -> Io {
+        // Reading through a BufReader of our own could take lines that the
+        // REPL's line editor is meant to read, so stdin is read a line at a time.
+        Io {
+            read_line: Arc::new(Mutex::new(Box::new(|line: &mut String| stdin().read_line(line)))),
+            output: Arc::new(Mutex::new(Box::new(io::stdout()))),
+            errors: Arc::new(Mutex::new(Box::new(io::stderr()))),
+        }
+    }
+}
+
+pub fn initial_environment() -> ProtectedEnv {
+    initial_environment_with_io(Io::stdio())
+}
 
+#[deprecated(note = "renamed to initial_environment")]
 pub fn initial_enviroment() -> ProtectedEnv {
+    initial_environment()
+}
+
+pub fn initial_environment_with_io(io: Io) -> ProtectedEnv {
+    let (print_io, write_io, inspect_io, input_io) = (io.clone(), io.clone(), io.clone(), io);
+    // println is another name for print
+    let print = prim!(move |args: Vec<Value>| {
+        print_values(&mut *print_io.output.lock().unwrap(), &args);
+        Value::Number(0.0)
+    });
     let builtins = vec![
-        ( s!("print"), prim!(|args: Vec<Value>| {
-            for arg in args {
-                print!("{} ", arg);
-            }
-            println!("");
+        ( s!("print"), print.clone() ),
+        ( s!("println"), print ),
+        ( s!("write"), prim!(move |args: Vec<Value>| {
+            write_values(&mut *write_io.output.lock().unwrap(), &args);
             Value::Number(0.0)
         })),
-        ( s!("input"), prim!(|_| {
+        ( s!("exit"), prim!(|args: Vec<Value>| {
+            let code = match args.get(0).and_then(Value::as_f64) {
+                Some(n) => n as i32,
+                None => 0,
+            };
+            ::std::process::exit(code)
+        })),
+        ( s!("assert"), fallible_prim!(|args: Vec<Value>| {
+            if args.len() != 1 && args.len() != 2 {
+                return Err(Error::ArityOutOfRange { name: s!("assert"), min: 1, max: 2, got: args.len() });
+            }
+            if args[0].truthy() {
+                Ok(Value::Number(0.0))
+            } else {
+                Err(Error::AssertionFailed(match args.get(1) {
+                    Some(msg) => format!("{}", msg),
+                    None => s!("assertion failed"),
+                }))
+            }
+        })),
+        ( s!("inspect"), fallible_prim!(move |args: Vec<Value>| {
+            if args.len() != 1 {
+                return Err(Error::ArityMismatch { name: s!("inspect"), expected: 1, got: args.len() });
+            }
+            Ok(inspect(&mut *inspect_io.errors.lock().unwrap(), &args[0]))
+        })),
+        ( s!("input"), prim!(move |_| {
             let mut in_ = String::new();
-            stdin().read_line(&mut in_).unwrap();
-            in_.pop();
+            (*input_io.read_line.lock().unwrap())(&mut in_).unwrap();
+            if in_.ends_with('\n') {
+                in_.pop();
+            }
             Value::Str(in_)
         })),
-        ( s!("math"), {
-            let conts = vec![
-                ( s!("ceil"), prim!(|args: Vec<Value>| {
-                    if let Value::Number(n) = args[0] {
-                        Value::Number(n.ceil())
-                    } else {
-                        panic!("math.ceil was passed {:?}, not a number!", args[0])
-                    }
-                })),
-                ( s!("floor"), prim!(|args: Vec<Value>| {
-                    if let Value::Number(n) = args[0] {
-                        Value::Number(n.floor())
-                    } else {
-                        panic!("math.floor was passed {:?}, not a number!", args[0])
-                    }
-                })),
-                ( s!("sqrt"), prim!(|args: Vec<Value>| {
-                    if let Value::Number(n) = args[0] {
-                        Value::Number(n.sqrt())
-                    } else {
-                        panic!("math.sqrt was passed {:?}, not a number!", args[0])
+        ( s!("read_file"), fallible_prim!(|args: Vec<Value>| {
+            match args.get(0) {
+                Some(&Value::Str(ref path)) if args.len() == 1 => {
+                    let mut contents = String::new();
+                    match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+                        Ok(_) => Ok(Value::Str(contents)),
+                        Err(e) => Err(Error::IoError(format!("could not read {:?}: {}", path, e))),
                     }
-                })),
-                ( s!("sin"), prim!(|args: Vec<Value>| {
-                    if let Value::Number(n) = args[0] {
-                        Value::Number(n.sin())
-                    } else {
-                        panic!("math.sin was passed {:?}, not a number!", args[0])
-                    }
-                })),
-                ( s!("cos"), prim!(|args: Vec<Value>| {
-                    if let Value::Number(n) = args[0] {
-                        Value::Number(n.cos())
-                    } else {
-                        panic!("math.cos was passed {:?}, not a number!", args[0])
-                    }
-                })),
-                ( s!("tan"), prim!(|args: Vec<Value>| {
-                    if let Value::Number(n) = args[0] {
-                        Value::Number(n.tan())
-                    } else {
-                        panic!("math.tan was passed {:?}, not a number!", args[0])
+                },
+                _ if args.len() != 1 => Err(Error::ArityMismatch { name: s!("read_file"), expected: 1, got: args.len() }),
+                _ => Err(Error::InvalidTypes(format!("read_file needs a path string, not {}", display_for_error(&args[0])))),
+            }
+        })),
+        ( s!("write_file"), fallible_prim!(|args: Vec<Value>| {
+            if args.len() != 2 {
+                return Err(Error::ArityMismatch { name: s!("write_file"), expected: 2, got: args.len() });
+            }
+            match (&args[0], &args[1]) {
+                (&Value::Str(ref path), &Value::Str(ref contents)) => {
+                    match File::create(path).and_then(|mut file| file.write_all(contents.as_bytes())) {
+                        Ok(_) => Ok(Value::Number(0.0)),
+                        Err(e) => Err(Error::IoError(format!("could not write {:?}: {}", path, e))),
                     }
-                })),
-            ];
-            Value::Module(Arc::new(Mutex::new(RefCell::new(Enviroment::extend(conts, None)))))
-        }),
-    ];
-    let env = Arc::new(Mutex::new(RefCell::new(Enviroment::extend(builtins, None))));
-    // builtins are baked directly into the exacutable in order to
-    // make sure that they are always available
-    load_module_into_env(include_str!("stdlib/builtins.nemo"), env.clone(), ".").unwrap();
-    env
-}
-
-pub fn eval<'a, 'b>(ast: &'a Expr, env: ProtectedEnv, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>) -> Result<Value, Error<'b>> {
-    match *ast {
-        Expr::Number(n) => Ok(Value::Number(n)),
-        Expr::Str(ref s) => Ok(Value::Str(s.clone())),
-        Expr::Neg(ref n) => {
-            match **n {
-                Expr::Number(n) => Ok(Value::Number(-n)),
-                // neg only works for number literals right now.
-                _ => unreachable!(),
+                },
+                (path, contents) => Err(Error::InvalidTypes(format!("write_file needs a path and contents that are strings, not {} and {}", display_for_error(path), display_for_error(contents)))),
             }
-        }
-        Expr::FinishedPipe => Ok(Value::FinishedPipe),
-        Expr::Bool(b) => Ok(Value::Bool(b)),
-        Expr::Lambda(ref args, ref body) => {
-            let def = Definition::new(Prototype::new("lambda".to_owned(), args.clone()), body.clone());
-            let func = Value::UserFunc(def, env.clone());
-            Ok(func)
-        }
-        Expr::Push(ref val) => {
-            let v = eval(val, env, this.clone(), next.clone())?;
-            next.lock().unwrap().push(v);
-            Ok(Value::Number(0.0))
-        },
-        Expr::Pull => {
-            let val = this.lock().unwrap().pop();
-            Ok(val)
-        },
-        Expr::Binary(ref lhs, Op::Pipe, ref rhs) => {
-            let (send, recv) = queue::make(1);
-            let (send, recv) = (Arc::new(Mutex::new(send)), Arc::new(Mutex::new(recv)));
-            let l = lhs.clone();
-            let e = env.clone();
-            thread::spawn(move|| {
-                eval(&l, e, this.clone(), send.clone()).unwrap();
-                send.lock().unwrap().push(Value::FinishedPipe);
+        })),
+        ( s!("normalize"), fallible_prim!(|args: Vec<Value>| {
+            if args.is_empty() || args.len() > 2 {
+                return Err(Error::ArityOutOfRange { name: s!("normalize"), min: 1, max: 2, got: args.len() });
+            }
+            let mode = match args.get(1) {
+                Some(&Value::Str(ref mode)) => mode.clone(),
+                None => s!("NFC"),
+                Some(other) => return Err(Error::InvalidTypes(format!("normalize needs a string as the mode, not {}", display_for_error(other)))),
+            };
+            if let Value::Str(ref s) = args[0] {
+                Ok(Value::Str(match mode.as_str() {
+                    "NFC" => s.nfc().collect(),
+                    "NFD" => s.nfd().collect(),
+                    "NFKC" => s.nfkc().collect(),
+                    "NFKD" => s.nfkd().collect(),
+                    _ => return Err(Error::InvalidArgument(format!("normalize was passed the unknown mode {:?}, expected 'NFC', 'NFD', 'NFKC' or 'NFKD'", mode))),
+                }))
+            } else {
+                Err(Error::InvalidTypes(format!("normalize needs a string, not {}", display_for_error(&args[0]))))
+            }
+        })),
+        ( s!("range"), pipe_prim!(|args: Vec<Value>, _, next: Arc<Mutex<queue::Producer<Value>>>| {
+            let (start, end, step) = match args.len() {
+                1 => (Value::Int(0), args[0].clone(), Value::Int(1)),
+                2 => (args[0].clone(), args[1].clone(), Value::Int(1)),
+                3 => (args[0].clone(), args[1].clone(), args[2].clone()),
+                got => return Err(Error::ArityOutOfRange { name: s!("range"), min: 1, max: 3, got }),
+            };
+            push_range(&start, &end, &step, &next)?;
+            Ok(Value::Number(0.0))
+        })),
+        ( s!("map"), pipe_prim!(|args: Vec<Value>, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>| {
+            let f = one_function_arg("map", args)?;
+            while let Some(x) = pull_from(&this) {
+                let y = apply(&f, vec![x], this.clone(), next.clone())?;
+                push_to(&next, y)?;
+            }
+            Ok(Value::Number(0.0))
+        })),
+        ( s!("filter"), pipe_prim!(|args: Vec<Value>, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>| {
+            let f = one_function_arg("filter", args)?;
+            while let Some(x) = pull_from(&this) {
+                if apply(&f, vec![x.clone()], this.clone(), next.clone())?.truthy() {
+                    push_to(&next, x)?;
+                }
+            }
+            Ok(Value::Number(0.0))
+        })),
+        ( s!("foreach"), pipe_prim!(|args: Vec<Value>, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>| {
+            let f = one_function_arg("foreach", args)?;
+            while let Some(x) = pull_from(&this) {
+                apply(&f, vec![x], this.clone(), next.clone())?;
+            }
+            Ok(Value::Number(0.0))
+        })),
+        ( s!("reduce"), pipe_prim!(|args: Vec<Value>, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>| {
+            if args.len() != 2 {
+                return Err(Error::ArityMismatch { name: s!("reduce"), expected: 2, got: args.len() });
+            }
+            let mut args = args;
+            let f = args.pop().unwrap();
+            let mut acc = args.pop().unwrap();
+            while let Some(x) = pull_from(&this) {
+                acc = apply(&f, vec![acc, x], this.clone(), next.clone())?;
+            }
+            Ok(acc)
+        })),
+        ( s!("now"), fallible_prim!(|args: Vec<Value>| {
+            if !args.is_empty() {
+                return Err(Error::ArityMismatch { name: s!("now"), expected: 0, got: args.len() });
+            }
+            let since_epoch = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).unwrap_or_default();
+            Ok(Value::Number(since_epoch.as_secs() as f64 + since_epoch.subsec_nanos() as f64 / 1e9))
+        })),
+        // Every pipe stage has its own thread, so sleeping in one stage
+        // doesn't hold up the others.
+        ( s!("sleep"), fallible_prim!(|args: Vec<Value>| {
+            let ms = match one_function_arg("sleep", args)? {
+                Value::Int(ms) if ms >= 0 => ms as f64,
+                Value::Number(ms) if ms >= 0.0 && ms.is_finite() => ms,
+                other => return Err(Error::InvalidTypes(format!("sleep needs a number of milliseconds that isn't negative, not {}", display_for_error(&other)))),
+            };
+            thread::sleep(::std::time::Duration::new((ms / 1000.0) as u64, ((ms % 1000.0) * 1e6) as u32));
+            Ok(Value::Number(0.0))
+        })),
+        // The function's result is thrown away, only the time it took is returned.
+        ( s!("time_it"), pipe_prim!(|args: Vec<Value>, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>| {
+            let f = one_function_arg("time_it", args)?;
+            let start = ::std::time::Instant::now();
+            apply(&f, vec![], this, next)?;
+            let elapsed = start.elapsed();
+            Ok(Value::Number(elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9))
+        })),
+        ( s!("random"), fallible_prim!(|args: Vec<Value>| {
+            if !args.is_empty() {
+                return Err(Error::ArityMismatch { name: s!("random"), expected: 0, got: args.len() });
+            }
+            Ok(Value::Number(random_float()))
+        })),
+        ( s!("random_int"), fallible_prim!(|args: Vec<Value>| {
+            if args.len() != 2 {
+                return Err(Error::ArityMismatch { name: s!("random_int"), expected: 2, got: args.len() });
+            }
+            let (lo, hi) = (integer_arg("random_int", &args[0])?, integer_arg("random_int", &args[1])?);
+            if lo > hi {
+                return Err(Error::InvalidArgument(format!("random_int needs lo <= hi, but got {} and {}", lo, hi)));
+            }
+            Ok(Value::Int(random_int(lo, hi)))
+        })),
+        ( s!("seed"), fallible_prim!(|args: Vec<Value>| {
+            if args.len() != 1 {
+                return Err(Error::ArityMismatch { name: s!("seed"), expected: 1, got: args.len() });
+            }
+            seed_random(integer_arg("seed", &args[0])? as u64);
+            Ok(Value::Number(0.0))
+        })),
+        ( s!("is_nan"), fallible_prim!(|args: Vec<Value>| number_test("is_nan", args, f64::is_nan))),
+        ( s!("is_infinite"), fallible_prim!(|args: Vec<Value>| number_test("is_infinite", args, f64::is_infinite))),
+        ( s!("is_finite"), fallible_prim!(|args: Vec<Value>| number_test("is_finite", args, f64::is_finite))),
+        ( s!("len"), fallible_prim!(|args: Vec<Value>| {
+            if args.len() != 1 {
+                return Err(Error::ArityMismatch { name: s!("len"), expected: 1, got: args.len() });
+            }
+            match args[0] {
+                Value::Str(ref s) => Ok(Value::Int(UnicodeSegmentation::graphemes(s.as_str(), true).count() as i64)),
+                ref other => Err(Error::InvalidTypes(format!("len was passed {}, which has no length", display_for_error(other)))),
+            }
+        })),
+        ( s!("is_empty"), fallible_prim!(|args: Vec<Value>| {
+            match one_function_arg("is_empty", args)? {
+                // a string has no graphemes exactly when it has no bytes
+                Value::Str(ref s) => Ok(Value::Bool(s.is_empty())),
+                other => Err(Error::InvalidTypes(format!("is_empty was passed {}, which has no length", display_for_error(&other)))),
+            }
+        })),
+        ( s!("contains"), fallible_prim!(|args: Vec<Value>| {
+            if args.len() != 2 {
+                return Err(Error::ArityMismatch { name: s!("contains"), expected: 2, got: args.len() });
+            }
+            operations::contains(&args[0], &args[1])
+        })),
+        ( s!("starts_with"), fallible_prim!(|args: Vec<Value>| {
+            let (s, prefix) = two_string_args("starts_with", &args)?;
+            let s: Vec<&str> = UnicodeSegmentation::graphemes(s, true).collect();
+            let prefix: Vec<&str> = UnicodeSegmentation::graphemes(prefix, true).collect();
+            Ok(Value::Bool(s.starts_with(&prefix)))
+        })),
+        ( s!("ends_with"), fallible_prim!(|args: Vec<Value>| {
+            let (s, suffix) = two_string_args("ends_with", &args)?;
+            let s: Vec<&str> = UnicodeSegmentation::graphemes(s, true).collect();
+            let suffix: Vec<&str> = UnicodeSegmentation::graphemes(suffix, true).collect();
+            Ok(Value::Bool(s.ends_with(&suffix)))
+        })),
+        ( s!("modulo"), fallible_prim!(|args: Vec<Value>| {
+            if args.len() != 2 {
+                return Err(Error::ArityMismatch { name: s!("modulo"), expected: 2, got: args.len() });
+            }
+            // Unlike %, the result takes the sign of the divisor.
+            if let (&Value::Int(a), &Value::Int(b)) = (&args[0], &args[1]) {
+                if let Some(r) = a.checked_rem(b) {
+                    return Ok(Value::Int(if r != 0 && (r < 0) != (b < 0) { r + b } else { r }));
+                }
+            }
+            if let (Some(a), Some(b)) = (args[0].as_f64(), args[1].as_f64()) {
+                let r = a % b;
+                Ok(Value::Number(if r != 0.0 && (r < 0.0) != (b < 0.0) { r + b } else { r }))
+            } else {
+                Err(Error::InvalidTypes(format!("modulo needs two numbers, not {} and {}", display_for_error(&args[0]), display_for_error(&args[1]))))
+            }
+        })),
+        ( s!("format_number"), fallible_prim!(|args: Vec<Value>| {
+            if args.is_empty() || args.len() > 3 {
+                return Err(Error::ArityOutOfRange { name: s!("format_number"), min: 1, max: 3, got: args.len() });
+            }
+            let n = match args[0].as_f64() {
+                Some(n) => n,
+                None => return Err(Error::InvalidTypes(format!("format_number needs a number, not {}", display_for_error(&args[0])))),
+            };
+            // format_number(n, decimals)
+            if args.len() == 2 && args[1].as_f64().is_some() {
+                let decimals = integer_arg("format_number", &args[1])?;
+                if decimals > MAX_FORMAT_PLACES as i64 {
+                    return Err(Error::InvalidArgument(format!("format_number can show at most {} decimal places, not {}", MAX_FORMAT_PLACES, decimals)));
+                }
+                return Ok(Value::Str(round_to_decimals(n, decimals)));
+            }
+            let style = match args.get(1) {
+                Some(&Value::Str(ref style)) => style.clone(),
+                None => s!("fixed"),
+                Some(other) => return Err(Error::InvalidTypes(format!("format_number needs a string as the style, not {}", display_for_error(other)))),
+            };
+            let places = match args.get(2) {
+                Some(places) => match integer_arg("format_number", places)? {
+                    places if places >= 0 && places <= MAX_FORMAT_PLACES as i64 => places as usize,
+                    places => return Err(Error::InvalidArgument(format!("format_number needs from 0 to {} places, not {}", MAX_FORMAT_PLACES, places))),
+                },
+                None => if style == "grouped" { 0 } else { 2 },
+            };
+            match format_number(n, &style, places) {
+                Some(formatted) => Ok(Value::Str(formatted)),
+                None => Err(Error::InvalidArgument(format!("format_number was passed the unknown style {:?}, expected 'fixed', 'sci' or 'grouped'", style))),
+            }
+        })),
+        ( s!("math"), {
+            let conts = vec![
+                ( s!("ceil"), math_function("ceil", f64::ceil) ),
+                ( s!("floor"), math_function("floor", f64::floor) ),
+                ( s!("sqrt"), math_function("sqrt", f64::sqrt) ),
+                ( s!("sin"), math_function("sin", f64::sin) ),
+                ( s!("cos"), math_function("cos", f64::cos) ),
+                ( s!("tan"), math_function("tan", f64::tan) ),
+            ];
+            Value::Module(Arc::new(Mutex::new(RefCell::new(Environment::extend(conts, None)))))
+        }),
+    ];
+    let env = Arc::new(Mutex::new(RefCell::new(Environment::extend(builtins, None))));
+    // builtins are baked directly into the exacutable in order to
+    // make sure that they are always available
+    load_module_into_env(include_str!("stdlib/builtins.nemo"), env.clone(), ".").unwrap();
+    // eval_string needs the environment it runs code in, so it is added last.
+    // It holds a Weak so that the environment doesn't keep itself alive.
+    let weak = Arc::downgrade(&env);
+    let eval_string = pipe_prim!(move |args: Vec<Value>, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>| {
+        if args.len() != 1 {
+            return Err(Error::ArityMismatch { name: s!("eval_string"), expected: 1, got: args.len() });
+        }
+        match (&args[0], weak.upgrade()) {
+            (&Value::Str(ref src), Some(env)) => eval_source(src, env, this, next),
+            (&Value::Str(_), None) => Err(Error::UndefinedName(s!("eval_string was called after its environment was dropped"))),
+            (other, _) => Err(Error::InvalidTypes(format!("eval_string needs a string, not {}", display_for_error(other)))),
+        }
+    });
+    {
+        let lock = env.lock().unwrap();
+        lock.borrow_mut().set(s!("eval_string"), Some(eval_string));
+    }
+    env
+}
+
+/// Parses and evaluates `src` as an expression in `env`, for `eval_string`.
+/// A syntax error is returned as `Error::SyntaxError` rather than a panic, and
+/// a `return` in `src` just ends the evaluation with that value.
+pub fn eval_source(src: &str, env: ProtectedEnv, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>) -> Result<Value, Error<'static>> {
+    let expr = match parser::parse_Expr(src) {
+        Ok(expr) => expr,
+        Err(e) => return Err(Error::SyntaxError(format!("{:?}", e))),
+    };
+    match eval(&expr, env, this, next) {
+        Err(Error::EarlyReturn(val)) => Ok(val),
+        Err(Error::LoopBreak) => Err(Error::LoopControlOutsideLoop(s!("break used outside of a loop in eval_string"))),
+        Err(Error::LoopContinue) => Err(Error::LoopControlOutsideLoop(s!("continue used outside of a loop in eval_string"))),
+        r => r,
+    }
+}
+
+/// The builtins a sandbox leaves out, because they reach outside of it
+const SANDBOXED_BUILTINS: &[&str] = &["exit", "input", "read_file", "write_file", "seed"];
+
+/// A fresh environment for running snippets of code in. Nothing defined in one
+/// sandbox can be seen from another.
+///
+/// Code in a sandbox can't end the process, read stdin, or touch files, so
+/// `exit`, `input`, `read_file` and `write_file` aren't defined and `use` is an
+/// `InvalidArgument` error. It can still print to stdout. `random` draws from
+/// the one generator the whole process shares, so `seed` isn't defined either.
+///
+/// The pipe buffer size and the call depth limit are process-wide settings,
+/// and apply to sandboxes as well as to the code that runs them.
+pub struct Sandbox {
+    env: ProtectedEnv,
+}
+
+pub fn sandbox() -> Sandbox {
+    let env = initial_environment();
+    {
+        let lock = env.lock().unwrap();
+        let mut globals = lock.borrow_mut();
+        for name in SANDBOXED_BUILTINS {
+            globals.current_frame.remove(*name);
+        }
+    }
+    Sandbox { env }
+}
+
+impl Sandbox {
+    /// Runs `src`, which is either an expression or top level definitions, and
+    /// returns the expression's value or 0 for definitions. Code in the sandbox
+    /// runs outside of any pipeline, on a thread of its own with a stack of
+    /// `SANDBOX_STACK_SIZE`, and calls nest only as deeply as fit in it. A panic
+    /// in that thread is returned as `Error::Panicked` instead of reaching the
+    /// caller.
+    pub fn run(&self, src: &str) -> Result<Value, Error<'static>> {
+        let env = self.env.clone();
+        let src = src.to_owned();
+        let runner = spawn_with_stack(SANDBOX_STACK_SIZE, move || run_in_sandbox(&src, env));
+        match runner {
+            Ok(runner) => match runner.join() {
+                Ok(result) => result,
+                Err(payload) => Err(Error::Panicked(panic_message(&*payload))),
+            },
+            Err(e) => Err(Error::IoError(format!("could not start the sandbox's thread: {}", e))),
+        }
+    }
+}
+
+// The message a panic was started with, if it was a string
+fn panic_message(payload: &(::std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        s!("a panic with no message")
+    }
+}
+
+fn run_in_sandbox(src: &str, env: ProtectedEnv) -> Result<Value, Error<'static>> {
+    let err = match eval_source(src, env.clone(), detached_consumer(), detached_producer()) {
+        Err(Error::SyntaxError(e)) => e,
+        r => return r,
+    };
+    let program = match parser::parse_Program(src) {
+        Ok(program) => program,
+        Err(_) => return Err(Error::SyntaxError(err)),
+    };
+    for top in &program {
+        if let Top::Use(..) = *top {
+            return Err(Error::InvalidArgument(s!("use can't load modules in a sandbox")));
+        }
+    }
+    match load_module_into_env(src, env, ".") {
+        Ok(_) => Ok(Value::Number(0.0)),
+        Err(LoadError::ParseError(e)) => Err(Error::SyntaxError(format!("{:?}", e))),
+        Err(e) => Err(Error::IoError(format!("{}", e))),
+    }
+}
+
+/// Calls `func` with `args` the same way a call expression does.
+pub fn apply<'a>(func: &Value, args: Vec<Value>, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>) -> Result<Value, Error<'a>> {
+    match *func {
+        Value::PrimFunc(ref f) => {
+            f(args)
+        },
+        Value::PipeFunc(ref f) => {
+            f(args, this, next)
+        },
+        Value::UserFunc(ref def, ref body_env) => {
+            let (required, total) = (def.prototype.required_args(), def.prototype.args.len());
+            if args.len() < required || args.len() > total {
+                let name = def.prototype.name.clone();
+                return Err(if required == total {
+                    Error::ArityMismatch { name, expected: total, got: args.len() }
+                } else {
+                    Error::ArityOutOfRange { name, min: required, max: total, got: args.len() }
+                });
+            }
+            let mut args: Vec<Option<Value>> = args.into_iter().map(Some).collect();
+            args.resize(def.prototype.args.len(), None);
+            call_user_function(def, body_env, args, this, next)
+        }
+        _ => Err(Error::InvalidTypes(format!("{} is not a function!", display_for_error(func))))
+    }
+}
+
+/// Calls `func` with positional arguments followed by named ones, like
+/// `f(1, y: 2)`. Only functions written in nemo have parameter names.
+pub fn apply_named<'a>(func: &Value, args: Vec<Value>, named: Vec<(String, Value)>, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>) -> Result<Value, Error<'a>> {
+    let (def, body_env) = match *func {
+        Value::UserFunc(ref def, ref body_env) => (def, body_env),
+        Value::PrimFunc(_) | Value::PipeFunc(_) => return Err(Error::InvalidArgument(s!("builtins can't be given named arguments"))),
+        _ => return Err(Error::InvalidTypes(format!("{} is not a function!", display_for_error(func)))),
+    };
+    let params = &def.prototype.args;
+    if args.len() > params.len() {
+        return Err(Error::ArityMismatch { name: def.prototype.name.clone(), expected: params.len(), got: args.len() });
+    }
+    let mut slots: Vec<Option<Value>> = args.into_iter().map(Some).collect();
+    slots.resize(params.len(), None);
+    for (name, val) in named {
+        let i = match params.iter().position(|param| *param == name) {
+            Some(i) => i,
+            None => return Err(Error::InvalidArgument(format!("{} has no parameter named {}", def.prototype.name, name))),
+        };
+        if slots[i].is_some() {
+            return Err(Error::InvalidArgument(format!("{} was given {} more than once", def.prototype.name, name)));
+        }
+        slots[i] = Some(val);
+    }
+    call_user_function(def, body_env, slots, this, next)
+}
+
+// Runs a function with one slot per parameter. Empty slots get their
+// parameter's default, which is evaluated on every call in the scope the
+// function was defined in.
+fn call_user_function<'a>(def: &Definition, body_env: &ProtectedEnv, args: Vec<Option<Value>>, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>) -> Result<Value, Error<'a>> {
+    let _depth = CallDepthGuard::enter()?;
+    let required = def.prototype.required_args();
+    let mut new_bindings = Vec::with_capacity(args.len());
+    for (i, (name, arg)) in def.prototype.args.iter().zip(args).enumerate() {
+        let val = match arg {
+            Some(val) => val,
+            None if i >= required => eval(&def.prototype.defaults[i - required], body_env.clone(), this.clone(), next.clone())?,
+            None => return Err(Error::InvalidArgument(format!("{} was not given {}", def.prototype.name, name))),
+        };
+        new_bindings.push((name.clone(), val));
+    }
+    let new_env = Arc::new(
+                  Mutex::new(
+                  RefCell::new(
+                      Environment::extend(new_bindings, Some(body_env.clone())
+                  ))));
+    match eval(&def.body, new_env, this, next) {
+        Err(Error::EarlyReturn(val)) => Ok(val),
+        // Loops can't see past the function they are in.
+        Err(Error::LoopBreak) => Err(Error::LoopControlOutsideLoop(format!("break used outside of a loop in {}", def.prototype.name))),
+        Err(Error::LoopContinue) => Err(Error::LoopControlOutsideLoop(format!("continue used outside of a loop in {}", def.prototype.name))),
+        r => r,
+    }
+}
+
+/// Writes each value followed by a space, then a newline, the way `print` does.
+pub fn print_values<W: Write>(out: &mut W, args: &[Value]) {
+    for arg in args {
+        let _ = write!(out, "{} ", arg);
+    }
+    let _ = writeln!(out);
+}
+
+/// Writes the values with nothing between or after them, the way `write` does.
+pub fn write_values<W: Write>(out: &mut W, args: &[Value]) {
+    for arg in args {
+        let _ = write!(out, "{}", arg);
+    }
+    let _ = out.flush();
+}
+
+/// Writes the Debug form of `v` to `out` and returns `v`, so that `inspect` can
+/// be wrapped around any expression without changing what it evaluates to.
+pub fn inspect<W: Write>(out: &mut W, v: &Value) -> Value {
+    let _ = writeln!(out, "{:?}", v);
+    v.clone()
+}
+
+// Pulls the next value for a pipe stage, or None once the pipe is finished.
+fn pull_from(this: &Arc<Mutex<queue::Consumer<Value>>>) -> Option<Value> {
+    match pull(this) {
+        Value::FinishedPipe => None,
+        x => Some(x),
+    }
+}
+
+// A function of the math module, which takes one number
+fn math_function(name: &'static str, f: fn(f64) -> f64) -> Value {
+    fallible_prim!(move |args: Vec<Value>| {
+        if args.len() != 1 {
+            return Err(Error::ArityMismatch { name: format!("math.{}", name), expected: 1, got: args.len() });
+        }
+        match args[0].as_f64() {
+            Some(n) => Ok(Value::Number(f(n))),
+            None => Err(Error::InvalidTypes(format!("math.{} needs a number, not {}", name, display_for_error(&args[0])))),
+        }
+    })
+}
+
+fn one_function_arg<'a>(name: &str, mut args: Vec<Value>) -> Result<Value, Error<'a>> {
+    if args.len() != 1 {
+        return Err(Error::ArityMismatch { name: name.to_owned(), expected: 1, got: args.len() });
+    }
+    Ok(args.remove(0))
+}
+
+// The two strings in `args`, for builtins like starts_with
+fn two_string_args<'a, 'v>(name: &str, args: &'v [Value]) -> Result<(&'v str, &'v str), Error<'a>> {
+    match *args {
+        [Value::Str(ref s1), Value::Str(ref s2)] => Ok((s1, s2)),
+        [ref v1, ref v2] => Err(Error::InvalidTypes(format!("{} needs two strings, not {} and {}", name, display_for_error(v1), display_for_error(v2)))),
+        _ => Err(Error::ArityMismatch { name: name.to_owned(), expected: 2, got: args.len() }),
+    }
+}
+
+// Asks `test` about the one number in `args`. Ints are always finite.
+fn number_test<'a>(name: &str, args: Vec<Value>, test: fn(f64) -> bool) -> Result<Value, Error<'a>> {
+    match one_function_arg(name, args)? {
+        Value::Int(n) => Ok(Value::Bool(test(n as f64))),
+        Value::Number(n) => Ok(Value::Bool(test(n))),
+        other => Err(Error::InvalidTypes(format!("{} needs a number, not {}", name, display_for_error(&other)))),
+    }
+}
+
+static RANDOM_INIT: Once = Once::new();
+static RANDOM_STATE: AtomicUsize = AtomicUsize::new(0);
+const RANDOM_GAMMA: usize = 0x9e3779b97f4a7c15u64 as usize;
+
+/// Makes `random` and `random_int` give the same numbers every run.
+pub fn seed_random(seed: u64) {
+    // Seeding first means the clock is never used
+    RANDOM_INIT.call_once(|| {});
+    RANDOM_STATE.store(seed as usize, Ordering::SeqCst);
+}
+
+// A splitmix64 generator, seeded from the clock the first time it is used
+// unless seed() has been called. Every call moves the state on atomically,
+// so pipe stages on other threads never get the same number.
+fn next_random() -> u64 {
+    RANDOM_INIT.call_once(|| {
+        let now = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH).unwrap_or_default();
+        RANDOM_STATE.store((now.as_secs() ^ now.subsec_nanos() as u64 ^ (now.subsec_nanos() as u64) << 32) as usize, Ordering::SeqCst);
+    });
+    let mut z = RANDOM_STATE.fetch_add(RANDOM_GAMMA, Ordering::SeqCst).wrapping_add(RANDOM_GAMMA) as u64;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// A random float in [0, 1).
+pub fn random_float() -> f64 {
+    (next_random() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A random integer from `lo` to `hi`, including both.
+pub fn random_int(lo: i64, hi: i64) -> i64 {
+    let span = (hi.wrapping_sub(lo) as u64).wrapping_add(1);
+    if span == 0 {
+        // lo and hi are the smallest and largest i64s
+        return next_random() as i64;
+    }
+    // Numbers past the last whole multiple of span are thrown away, so that
+    // every result is equally likely.
+    let limit = ::std::u64::MAX - ::std::u64::MAX % span;
+    loop {
+        let r = next_random();
+        if r < limit {
+            return lo.wrapping_add((r % span) as i64);
+        }
+    }
+}
+
+// Ints, and floats with no fractional part, for builtins that need an integer
+fn integer_arg<'a>(name: &str, v: &Value) -> Result<i64, Error<'a>> {
+    match *v {
+        Value::Int(n) => Ok(n),
+        Value::Number(n) if n.fract() == 0.0 && n >= i64::min_value() as f64 && n < i64::max_value() as f64 => Ok(n as i64),
+        _ => Err(Error::InvalidTypes(format!("{} needs integers, not {}", name, display_for_error(v)))),
+    }
+}
+
+// Pushes start, start + step, ... for as long as they are before end, counting
+// down when step is negative. Ints stay Ints unless any of the bounds is a float.
+fn push_range<'a>(start: &Value, end: &Value, step: &Value, next: &Arc<Mutex<queue::Producer<Value>>>) -> Result<(), Error<'a>> {
+    if let (&Value::Int(start), &Value::Int(end), &Value::Int(step)) = (start, end, step) {
+        if step == 0 {
+            return Err(Error::InvalidTypes(s!("range can not have a step of 0")));
+        }
+        let mut x = start;
+        while (step > 0 && x < end) || (step < 0 && x > end) {
+            push_to(next, Value::Int(x))?;
+            x = match x.checked_add(step) {
+                Some(x) => x,
+                None => break,
+            };
+        }
+        Ok(())
+    } else if let (Some(start), Some(end), Some(step)) = (start.as_f64(), end.as_f64(), step.as_f64()) {
+        if step == 0.0 {
+            return Err(Error::InvalidTypes(s!("range can not have a step of 0")));
+        }
+        let mut x = start;
+        while (step > 0.0 && x < end) || (step < 0.0 && x > end) {
+            push_to(next, Value::Number(x))?;
+            x += step;
+        }
+        Ok(())
+    } else {
+        Err(Error::InvalidTypes(format!("Invalid types for range: {}, {} and {}", display_for_error(start), display_for_error(end), display_for_error(step))))
+    }
+}
+
+pub fn eval<'a, 'b>(ast: &'a Expr, env: ProtectedEnv, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>) -> Result<Value, Error<'b>> {
+    match *ast {
+        Expr::Number(n) => Ok(Value::Number(n)),
+        Expr::Int(n) => Ok(Value::Int(n)),
+        Expr::Str(ref s) => Ok(Value::Str(s.clone())),
+        Expr::Stringify(ref e) => Ok(Value::Str(format!("{}", eval(e, env, this, next)?))),
+        Expr::Neg(ref n) => {
+            let val = eval(n, env, this, next)?;
+            operations::negate(&val)
+        }
+        Expr::FinishedPipe => Ok(Value::FinishedPipe),
+        Expr::Bool(b) => Ok(Value::Bool(b)),
+        Expr::Lambda(ref args, ref body) => {
+            let def = Definition::new(Prototype::new("lambda".to_owned(), args.clone()), body.clone());
+            let func = Value::UserFunc(Arc::new(def), env);
+            Ok(func)
+        }
+        Expr::Push(ref val) => {
+            let v = eval(val, env, this.clone(), next.clone())?;
+            push_to(&next, v)?;
+            Ok(Value::Number(0.0))
+        },
+        Expr::Pull => Ok(pull(&this)),
+        Expr::Range(ref start, ref end, inclusive) => {
+            if Arc::ptr_eq(&next, &detached_producer()) {
+                return Err(Error::RangeOutsidePipe(s!("a range literal has to be the start of a pipe, like (1..4) | f()")));
+            }
+            let start = eval(start, env.clone(), this.clone(), next.clone())?;
+            let end = eval(end, env, this, next.clone())?;
+            let (first, last) = match (start.as_f64(), end.as_f64()) {
+                (Some(first), Some(last)) => (first, last),
+                _ => return Err(Error::InvalidTypes(format!("Invalid types for range: {} and {}", display_for_error(&start), display_for_error(&end)))),
+            };
+            push_range(&start, &end, &Value::Int(1), &next)?;
+            // push_range stops before the end, so an inclusive range pushes it
+            // here if counting up from the start lands on it
+            if inclusive && first <= last && (last - first).fract() == 0.0 {
+                push_to(&next, end)?;
+            }
+            Ok(Value::Number(0.0))
+        },
+        Expr::Binary(ref lhs, Op::Pipe, ref rhs) => {
+            let (send, recv) = make_pipe(pipe_buffer_size());
+            let l = lhs.clone();
+            let e = env.clone();
+            let rhs_done = Arc::new(AtomicBool::new(false));
+            let done = rhs_done.clone();
+            // Where the left hand side leaves its error, if it has one
+            let lhs_error: Arc<Mutex<Option<Error<'static>>>> = Arc::new(Mutex::new(None));
+            let error_slot = lhs_error.clone();
+            spawn_pipe_stage(move|| {
+                if let Err(e) = eval(&l, e, this.clone(), send.clone()) {
+                    // Stored before the first FinishedPipe, so the right hand
+                    // side can't finish early without the error being there.
+                    *error_slot.lock().unwrap() = Some(e);
+                }
+                // Every pull after the end of the pipe gets FinishedPipe,
+                // until the right hand side doesn't need any more.
+                while !done.load(Ordering::SeqCst) {
+                    send.lock().unwrap().push(Value::FinishedPipe);
+                }
             });
-            eval(rhs, env.clone(), recv, next)
+            let result = eval(rhs, env, recv.clone(), next);
+            rhs_done.store(true, Ordering::SeqCst);
+            // Make room in case the left hand side is waiting to push another FinishedPipe.
+            recv.lock().unwrap().try_pop();
+            // An error on the left cut the pipe short, so it wins over whatever
+            // the right hand side made of the values it got.
+            let lhs_error = lhs_error.lock().unwrap().take();
+            match lhs_error {
+                Some(e) => Err(e),
+                None => result,
+            }
         },
         Expr::Binary(ref lhs, ref op, ref rhs) => {
             let l = eval(&*lhs, env.clone(), this.clone(), next.clone())?;
@@ -325,7 +1712,10 @@ pub fn eval<'a, 'b>(ast: &'a Expr, env: ProtectedEnv, this: Arc<Mutex<queue::Con
                 Op::Plus    => operations::plus(&l, &r),
                 Op::Minus   => operations::minus(&l, &r),
                 Op::Times   => operations::times(&l, &r),
+                Op::In      => operations::contains(&r, &l),
                 Op::Slash   => operations::slash(&l, &r),
+                Op::DoubleSlash => operations::double_slash(&l, &r),
+                Op::Power   => operations::power(&l, &r),
                 Op::Percent => operations::percent(&l, &r),
                 Op::Greater => operations::greater(&l, &r),
                 Op::Lesser  => operations::lesser(&l, &r),
@@ -333,6 +1723,11 @@ pub fn eval<'a, 'b>(ast: &'a Expr, env: ProtectedEnv, this: Arc<Mutex<queue::Con
                 Op::And     => operations::and(&l, &r),
                 Op::Or      => operations::or(&l, &r),
                 Op::NotEquals => operations::not_equals(&l, &r),
+                Op::BitAnd  => operations::bit_and(&l, &r),
+                Op::BitOr   => operations::bit_or(&l, &r),
+                Op::BitXor  => operations::bit_xor(&l, &r),
+                Op::Shl     => operations::shl(&l, &r),
+                Op::Shr     => operations::shr(&l, &r),
                 _ => Err(Error::Unimplemented(format!("Operation {:?} is not implemented yet", op)))
             }
         },
@@ -351,33 +1746,25 @@ pub fn eval<'a, 'b>(ast: &'a Expr, env: ProtectedEnv, this: Arc<Mutex<queue::Con
             for arg in arg_exprs {
                 args.push(eval(arg, env.clone(), this.clone(), next.clone())?);
             }
-            match func {
-                Value::PrimFunc(f) => {
-                    Ok(f(args))
-                },
-                Value::UserFunc(ref def, ref body_env) => {
-                    let mut new_bindings = vec![];
-                    for i in 0..def.prototype.args.len() {
-                        new_bindings.push((def.prototype.args[i].clone(), args[i].clone()))
-                    }
-                    let new_env = Arc::new(
-                                  Mutex::new(
-                                  RefCell::new(
-                                      Enviroment::extend(new_bindings, Some(body_env.clone())
-                                  ))));
-                    match eval(&def.body, new_env, this.clone(), next) {
-                        Err(Error::EarlyReturn(val)) => Ok(val),
-                        r => r,
-                    }
-                }
-                _ => Err(Error::InvalidTypes(format!("{} is not a function!", func)))
+            apply(&func, args, this, next)
+        },
+        Expr::NamedCall(ref func, ref arg_exprs, ref named_exprs) => {
+            let func = eval(func, env.clone(), this.clone(), next.clone())?;
+            let mut args = Vec::new();
+            for arg in arg_exprs {
+                args.push(eval(arg, env.clone(), this.clone(), next.clone())?);
+            }
+            let mut named = Vec::new();
+            for &(ref name, ref arg) in named_exprs {
+                named.push((name.clone(), eval(arg, env.clone(), this.clone(), next.clone())?));
             }
+            apply_named(&func, args, named, this, next)
         },
         Expr::Assignment(ref name, ref val) => {
             let name = name.clone();
             let evaled_val = eval(val, env.clone(), this.clone(), next.clone())?;
             let lock = env.lock().unwrap();
-            lock.borrow_mut().set(String::from(name), Some(evaled_val));
+            lock.borrow_mut().assign(name, evaled_val);
             Ok(Value::Number(0.0))
         },
         Expr::Block(ref expressions) => {
@@ -400,72 +1787,202 @@ pub fn eval<'a, 'b>(ast: &'a Expr, env: ProtectedEnv, this: Arc<Mutex<queue::Con
         Expr::Return(ref val) => {
             Err(Error::EarlyReturn(eval(val, env.clone(), this.clone(), next.clone())?))
         },
+        Expr::Break => Err(Error::LoopBreak),
+        Expr::Continue => Err(Error::LoopContinue),
+        Expr::Match(ref scrutinee, ref arms) => {
+            let val = eval(scrutinee, env.clone(), this.clone(), next.clone())?;
+            for &(ref pattern, ref body) in arms {
+                let matches = match *pattern {
+                    Pattern::Number(n) => val == Value::Number(n),
+                    Pattern::Str(ref s) => val == Value::Str(s.clone()),
+                    Pattern::Bool(b) => val == Value::Bool(b),
+                    Pattern::Wildcard => true,
+                };
+                if matches {
+                    return eval(body, env, this, next);
+                }
+            }
+            Err(Error::NoMatchingPattern(format!("no pattern matched {}", display_for_error(&val))))
+        },
         Expr::While(ref cond, ref body) => {
             while eval(cond, env.clone(), this.clone(), next.clone())?.truthy() {
-                eval(body, env.clone(), this.clone(), next.clone())?;
+                match eval(body, env.clone(), this.clone(), next.clone()) {
+                    Ok(_) | Err(Error::LoopContinue) => {},
+                    Err(Error::LoopBreak) => break,
+                    Err(e) => return Err(e),
+                }
             };
             Ok(Value::Number(0.0))
         },
+        Expr::DoWhile(ref body, ref cond) => {
+            // Like while, but the body runs once before cond is checked.
+            // continue skips to checking cond.
+            loop {
+                match eval(body, env.clone(), this.clone(), next.clone()) {
+                    Ok(_) | Err(Error::LoopContinue) => {},
+                    Err(Error::LoopBreak) => break,
+                    Err(e) => return Err(e),
+                }
+                if !eval(cond, env.clone(), this.clone(), next.clone())?.truthy() {
+                    break;
+                }
+            }
+            Ok(Value::Number(0.0))
+        },
+        Expr::Try(ref body, ref cleanup) => {
+            // The cleanup runs however the body finishes, including errors,
+            // returns and breaks, which are passed on afterwards. An error in
+            // the cleanup itself takes their place.
+            let result = eval(body, env.clone(), this.clone(), next.clone());
+            eval(cleanup, env, this, next)?;
+            result
+        },
         Expr::Index(ref source, ref index) => {
             let source = eval(source, env.clone(), this.clone(), next.clone())?;
             let index = eval(index, env.clone(), this.clone(), next.clone())?;
             operations::index(&source, &index)
         },
+        Expr::Slice(ref source, ref start, ref end) => {
+            let source = eval(source, env.clone(), this.clone(), next.clone())?;
+            let start = match *start {
+                Some(ref start) => Some(eval(start, env.clone(), this.clone(), next.clone())?),
+                None => None,
+            };
+            let end = match *end {
+                Some(ref end) => Some(eval(end, env, this, next)?),
+                None => None,
+            };
+            operations::slice(&source, start.as_ref(), end.as_ref())
+        },
         ref x => Err(Error::Unimplemented(format!("{:?} is not implemented yet", x))),
     }
 }
 
 mod operations {
     use super::*;
-    pub fn plus<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
-        if let (&Value::Number(n1), &Value::Number(n2)) = (l, r) {
-            Ok(Value::Number(n1 + n2))
+    // Ints stay Ints when the integer operation succeeds. Floats, a mix of
+    // the two, and integer overflow or division by zero use the float operation.
+    fn arithmetic<'a, I, F>(op: &str, l: &Value, r: &Value, int_op: I, float_op: F) -> Result<Value, Error<'a>>
+        where I: Fn(i64, i64) -> Option<i64>, F: Fn(f64, f64) -> f64 {
+        if let (&Value::Int(n1), &Value::Int(n2)) = (l, r) {
+            if let Some(n) = int_op(n1, n2) {
+                return Ok(Value::Int(n));
+            }
+        }
+        if let (Some(n1), Some(n2)) = (l.as_f64(), r.as_f64()) {
+            Ok(Value::Number(float_op(n1, n2)))
         } else {
-            Err(Error::InvalidTypes(format!("Invalid types for \"+\": {:?} and {:?}", l, r)))
+            Err(Error::InvalidTypes(format!("Invalid types for \"{}\": {} and {}", op, display_for_error(l), display_for_error(r))))
         }
     }
-    pub fn minus<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
-        if let (&Value::Number(n1), &Value::Number(n2)) = (l, r) {
-            Ok(Value::Number(n1 - n2))
-        } else {
-            Err(Error::InvalidTypes(format!("Invalid types for \"-\": {:?} and {:?}", l, r)))
+    pub fn plus<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
+        if let (&Value::Str(ref s1), &Value::Str(ref s2)) = (l, r) {
+            return Ok(Value::Str(format!("{}{}", s1, s2)));
         }
+        arithmetic("+", l, r, i64::checked_add, |n1, n2| n1 + n2)
+    }
+    pub fn minus<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
+        arithmetic("-", l, r, i64::checked_sub, |n1, n2| n1 - n2)
     }
+    /// The longest string, in bytes, that repeating a string with `*` can make.
+    pub const MAX_REPEATED_STRING_LEN: usize = 1 << 30;
+    // A string times a whole number that isn't negative, in either order,
+    // repeats the string. Any other count is an error, and so is a result
+    // longer than MAX_REPEATED_STRING_LEN.
     pub fn times<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
-        if let (&Value::Number(n1), &Value::Number(n2)) = (l, r) {
-            Ok(Value::Number(n1 * n2))
-        } else {
-            Err(Error::InvalidTypes(format!("Invalid types for \"*\": {:?} and {:?}", l, r)))
+        match (l, r) {
+            (&Value::Str(ref s), count) | (count, &Value::Str(ref s)) if count.as_f64().is_some() => {
+                match as_bits(count) {
+                    Some(n) if n >= 0 => match s.len().checked_mul(n as usize) {
+                        Some(len) if len <= MAX_REPEATED_STRING_LEN => Ok(Value::Str(s.repeat(n as usize))),
+                        _ => Err(Error::InvalidArgument(format!("Repeating a string {} times makes it longer than {} bytes", n, MAX_REPEATED_STRING_LEN))),
+                    },
+                    _ => Err(Error::InvalidTypes(format!("Strings can only be repeated a whole number of times that isn't negative, not {}", display_for_error(count)))),
+                }
+            },
+            _ => arithmetic("*", l, r, i64::checked_mul, |n1, n2| n1 * n2),
         }
     }
     pub fn slash<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
-        if let (&Value::Number(n1), &Value::Number(n2)) = (l, r) {
-            Ok(Value::Number(n1 / n2))
-        } else {
-            Err(Error::InvalidTypes(format!("Invalid types for \"/\": {:?} and {:?}", l, r)))
+        // "/" always divides exactly, even for two Ints.
+        arithmetic("/", l, r, |_, _| None, |n1, n2| n1 / n2)
+    }
+    pub fn double_slash<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
+        // Rounds toward negative infinity, so -7 // 2 is -4.
+        arithmetic("//", l, r, |n1, n2| {
+            n1.checked_div(n2).map(|q| if n1 % n2 != 0 && (n1 < 0) != (n2 < 0) { q - 1 } else { q })
+        }, |n1, n2| (n1 / n2).floor())
+    }
+    pub fn negate<'a>(v: &Value) -> Result<Value, Error<'a>> {
+        match *v {
+            Value::Int(n) => Ok(n.checked_neg().map(Value::Int).unwrap_or(Value::Number(-(n as f64)))),
+            Value::Number(n) => Ok(Value::Number(-n)),
+            _ => Err(Error::InvalidTypes(format!("Invalid type for \"-\": {}", display_for_error(v)))),
         }
     }
-    pub fn percent<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
-        if let (&Value::Number(n1), &Value::Number(n2)) = (l, r) {
-            Ok(Value::Number(n1 % n2))
-        } else {
-            Err(Error::InvalidTypes(format!("Invalid types for \"%\": {:?} and {:?}", l, r)))
+    pub fn power<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
+        // A negative exponent gives a fraction, so only raise Ints to a non-negative power
+        arithmetic("**", l, r, |n1, n2| {
+            if n2 >= 0 { checked_int_power(n1, n2) } else { None }
+        }, f64::powf)
+    }
+    // Like i64::checked_pow, which needs a newer Rust. Squares the base for
+    // each bit of the exponent, so big exponents only take a few steps.
+    fn checked_int_power(base: i64, exp: i64) -> Option<i64> {
+        let (mut base, mut exp, mut result) = (base, exp, 1i64);
+        while exp > 0 {
+            if exp & 1 == 1 {
+                result = match result.checked_mul(base) {
+                    Some(result) => result,
+                    None => return None,
+                };
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = match base.checked_mul(base) {
+                    Some(base) => base,
+                    None => return None,
+                };
+            }
         }
+        Some(result)
     }
+    pub fn percent<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
+        arithmetic("%", l, r, i64::checked_rem, |n1, n2| n1 % n2)
+    }
+    // Strings are ordered by Unicode code point, comparing the first chars that
+    // differ, so 'Zebra' < 'apple' and 'app' < 'apple'.
     pub fn greater<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
-        if let (&Value::Number(n1), &Value::Number(n2)) = (l, r) {
+        if let (&Value::Int(n1), &Value::Int(n2)) = (l, r) {
+            Ok(Value::Bool(n1 > n2))
+        } else if let (Some(n1), Some(n2)) = (l.as_f64(), r.as_f64()) {
+            if n1.is_nan() || n2.is_nan() {
+                return Err(Error::InvalidTypes(format!("NaN can't be ordered with \">\": {} and {}", display_for_error(l), display_for_error(r))));
+            }
             Ok(Value::Bool(n1 > n2))
+        } else if let (&Value::Str(ref s1), &Value::Str(ref s2)) = (l, r) {
+            Ok(Value::Bool(s1 > s2))
         } else {
-            Err(Error::InvalidTypes(format!("Invalid types for \">\": {:?} and {:?}", l, r)))
+            Err(Error::InvalidTypes(format!("Invalid types for \">\": {} and {}", display_for_error(l), display_for_error(r))))
         }
     }
     pub fn lesser<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
-        if let (&Value::Number(n1), &Value::Number(n2)) = (l, r) {
+        if let (&Value::Int(n1), &Value::Int(n2)) = (l, r) {
+            Ok(Value::Bool(n1 < n2))
+        } else if let (Some(n1), Some(n2)) = (l.as_f64(), r.as_f64()) {
+            if n1.is_nan() || n2.is_nan() {
+                return Err(Error::InvalidTypes(format!("NaN can't be ordered with \"<\": {} and {}", display_for_error(l), display_for_error(r))));
+            }
             Ok(Value::Bool(n1 < n2))
+        } else if let (&Value::Str(ref s1), &Value::Str(ref s2)) = (l, r) {
+            Ok(Value::Bool(s1 < s2))
         } else {
-            Err(Error::InvalidTypes(format!("Invalid types for \"<\": {:?} and {:?}", l, r)))
+            Err(Error::InvalidTypes(format!("Invalid types for \"<\": {} and {}", display_for_error(l), display_for_error(r))))
         }
     }
+    // Numbers compare the IEEE way: 0.0 = -0.0, and NaN isn't equal to
+    // anything, even itself. greater and lesser give an error for NaN instead
+    // of quietly answering false.
     pub fn equals<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
         Ok(Value::Bool(l == r))
     }
@@ -476,17 +1993,100 @@ mod operations {
         if let (&Value::Bool(n1), &Value::Bool(n2)) = (l, r) {
             Ok(Value::Bool(n1 && n2))
         } else {
-            Err(Error::InvalidTypes(format!("Invalid types for \"and\": {:?} and {:?}", l, r)))
+            Err(Error::InvalidTypes(format!("Invalid types for \"and\": {} and {}", display_for_error(l), display_for_error(r))))
         }
     }
     pub fn or<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
         if let (&Value::Bool(n1), &Value::Bool(n2)) = (l, r) {
             Ok(Value::Bool(n1 || n2))
         } else {
-            Err(Error::InvalidTypes(format!("Invalid types for \"or\": {:?} and {:?}", l, r)))
+            Err(Error::InvalidTypes(format!("Invalid types for \"or\": {} and {}", display_for_error(l), display_for_error(r))))
+        }
+    }
+    // Bitwise operators work on Ints, and on floats with no fractional part,
+    // which are treated as the Ints they are equal to.
+    fn as_bits(v: &Value) -> Option<i64> {
+        match *v {
+            Value::Int(n) => Some(n),
+            Value::Number(n) if n.fract() == 0.0 && n >= i64::min_value() as f64 && n < i64::max_value() as f64 => Some(n as i64),
+            _ => None,
+        }
+    }
+    fn bitwise<'a, F>(op: &str, l: &Value, r: &Value, f: F) -> Result<Value, Error<'a>>
+        where F: Fn(i64, i64) -> Option<i64> {
+        if let (Some(n1), Some(n2)) = (as_bits(l), as_bits(r)) {
+            if let Some(n) = f(n1, n2) {
+                return Ok(Value::Int(n));
+            }
+        }
+        Err(Error::InvalidTypes(format!("Invalid types for \"{}\": {} and {}", op, display_for_error(l), display_for_error(r))))
+    }
+    pub fn bit_and<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
+        bitwise("&", l, r, |n1, n2| Some(n1 & n2))
+    }
+    pub fn bit_or<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
+        bitwise("||", l, r, |n1, n2| Some(n1 | n2))
+    }
+    pub fn bit_xor<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
+        // On bools "^" is logical xor
+        if let (&Value::Bool(b1), &Value::Bool(b2)) = (l, r) {
+            return Ok(Value::Bool(b1 != b2));
+        }
+        bitwise("^", l, r, |n1, n2| Some(n1 ^ n2))
+    }
+    // Shifting by a negative amount or by 64 or more is an error
+    pub fn shl<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
+        bitwise("<<", l, r, |n1, n2| match n2 { 0..=63 => Some(n1 << n2), _ => None })
+    }
+    pub fn shr<'a>(l: &Value, r: &Value) -> Result<Value, Error<'a>> {
+        bitwise(">>", l, r, |n1, n2| match n2 { 0..=63 => Some(n1 >> n2), _ => None })
+    }
+    // Slices count graphemes like indexing does. A negative bound counts from
+    // the end, and bounds past either end are moved to that end, so a slice
+    // is never out of bounds. A start after the end gives an empty string.
+    pub fn slice<'a>(obj: &Value, start: Option<&Value>, end: Option<&Value>) -> Result<Value, Error<'a>> {
+        let s = match *obj {
+            Value::Str(ref s) => s,
+            _ => return Err(Error::InvalidTypes(format!("{} can not be sliced", display_for_error(obj)))),
+        };
+        let graphemes: Vec<&str> = UnicodeSegmentation::graphemes(s.as_str(), true).collect();
+        let len = graphemes.len() as i64;
+        let bound = |bound: Option<&Value>, default: i64| -> Result<usize, Error<'a>> {
+            let n = match bound {
+                None => default,
+                Some(v) => match as_bits(v) {
+                    Some(n) if n < 0 => len + n,
+                    Some(n) => n,
+                    None => return Err(Error::InvalidTypes(format!("{} can not be used as a slice bound", display_for_error(v)))),
+                },
+            };
+            Ok(::std::cmp::min(::std::cmp::max(n, 0), len) as usize)
+        };
+        let (start, end) = (bound(start, 0)?, bound(end, len)?);
+        if start >= end {
+            return Ok(Value::Str(s!("")));
+        }
+        Ok(Value::Str(graphemes[start..end].concat()))
+    }
+    // A string contains another when the other's graphemes appear in it in
+    // order, so 'e' is not in 'é' written as e and a combining accent.
+    // Everything contains nothing, so '' is in every string.
+    pub fn contains<'a>(collection: &Value, item: &Value) -> Result<Value, Error<'a>> {
+        match (collection, item) {
+            (&Value::Str(ref s), &Value::Str(ref sub)) => {
+                let s: Vec<&str> = UnicodeSegmentation::graphemes(s.as_str(), true).collect();
+                let sub: Vec<&str> = UnicodeSegmentation::graphemes(sub.as_str(), true).collect();
+                Ok(Value::Bool(sub.is_empty() || s.windows(sub.len()).any(|window| window == &sub[..])))
+            },
+            (&Value::Str(_), _) => Err(Error::InvalidTypes(format!("only strings can be searched for in strings, not {}", display_for_error(item)))),
+            _ => Err(Error::InvalidTypes(format!("{} can not be searched", display_for_error(collection)))),
         }
     }
     pub fn index<'a>(obj: &Value, index: &Value) -> Result<Value, Error<'a>> {
+        let index = &match *index {
+            Value::Int(n) => Value::Number(n as f64),
+            ref other => other.clone(),
+        };
         match *obj {
             Value::Str(ref s) => {
                 let s = s.clone();
@@ -499,19 +2099,19 @@ mod operations {
                         };
                         let chars: Vec<&str> = UnicodeSegmentation::graphemes(s.as_str(), true).collect();
                         if i >= chars.len() {
-                            return Err(Error::OutOfBoundIndex(format!("{:?} is greater than the length of {:?}", i, s)));
+                            return Err(Error::OutOfBoundIndex(format!("{:?} is greater than the length of {}", i, display_for_error(obj))));
                         }
                         let c = chars[i];
                         Ok(Value::Str(c.to_string()))
                     },
                     Value::Str(ref attr) => {
                         if attr == "len" {
-                            Ok(prim!(move |_| Value::Number(UnicodeSegmentation::graphemes(s.as_str(), true).collect::<Vec<_>>().len() as f64)))
+                            Ok(prim!(move |_| Value::Int(UnicodeSegmentation::graphemes(s.as_str(), true).collect::<Vec<_>>().len() as i64)))
                         } else {
                             Err(Error::UndefinedAttribute(format!("strings do not have the attribute {}", attr)))
                         }
                     },
-                    _ => Err(Error::InvalidTypes(format!("{:?} can not be used as an index", index)))
+                    _ => Err(Error::InvalidTypes(format!("{} can not be used as an index", display_for_error(index))))
                 }
             },
             Value::Module(ref env) => {
@@ -525,10 +2125,1251 @@ mod operations {
                             Err(Error::UndefinedName(format!("module has no attribute named {:?}", s)))
                         }
                     },
-                    _ => Err(Error::InvalidTypes(format!("{:?} can not be used as an attribute", index)))
+                    _ => Err(Error::InvalidTypes(format!("{} can not be used as an attribute", display_for_error(index))))
+                }
+            }
+            _ => Err(Error::InvalidTypes(format!("{} is not indexable", display_for_error(obj))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_str<'a>(src: &str, env: ProtectedEnv) -> Result<Value, Error<'a>> {
+        eval(&parser::parse_Expr(src).unwrap(), env, detached_consumer(), detached_producer())
+    }
+
+    #[test]
+    fn test_pipe_buffer_size_setting() {
+        assert_eq!(parse_pipe_buffer_size("64"), Some(64));
+        assert_eq!(parse_pipe_buffer_size("1"), Some(1));
+        for bad in &["0", "-1", "1.5", "", " 8", "lots"] {
+            assert_eq!(parse_pipe_buffer_size(bad), None, "{:?} should be ignored", bad);
+        }
+        assert_eq!(clamp_pipe_buffer_size(0), 1);
+        assert_eq!(clamp_pipe_buffer_size(3), 3);
+    }
+    #[test]
+    fn test_larger_pipe_buffer_lets_producer_run_ahead() {
+        // With room for three values, the producer's first three pushes go
+        // through before the consumer has pulled anything, and only the
+        // fourth has to wait.
+        let (next, this) = make_pipe(3);
+        for i in 0..3 {
+            assert!(next.lock().unwrap().try_push(Value::Int(i)).is_none(), "push {} should not wait", i);
+        }
+        assert_eq!(next.lock().unwrap().try_push(Value::Int(3)), Some(Value::Int(3)));
+        assert_eq!(pull(&this), Value::Int(0));
+        assert!(next.lock().unwrap().try_push(Value::Int(3)).is_none());
+        assert_eq!(pull_available(&this), vec![Value::Int(1), Value::Int(2), Value::Int(3)]);
+        // The default buffer only has room for one
+        let (next, _this) = make_pipe(DEFAULT_PIPE_BUFFER_SIZE);
+        assert!(next.lock().unwrap().try_push(Value::Int(0)).is_none());
+        assert!(next.lock().unwrap().try_push(Value::Int(1)).is_some());
+    }
+    #[test]
+    fn test_push_all_and_pull_available() {
+        let (next, this) = make_pipe(8);
+        push_all(&next, vec![Value::Int(1), Value::Int(2), Value::Int(3)]).unwrap();
+        assert_eq!(pull_available(&this), vec![Value::Int(1), Value::Int(2), Value::Int(3)]);
+        assert_eq!(pull_available(&this), vec![]);
+        push_all(&next, vec![Value::Int(4), Value::FinishedPipe, Value::FinishedPipe]).unwrap();
+        assert_eq!(pull_available(&this), vec![Value::Int(4), Value::FinishedPipe]);
+        assert_eq!(pull_available(&detached_consumer()), vec![Value::FinishedPipe]);
+        match push_all(&detached_producer(), vec![Value::Int(1)]) {
+            Err(Error::PushedToNone) => {},
+            r => panic!("expected PushedToNone, got {:?}", r),
+        }
+    }
+    // Run with `cargo test --release -- --ignored --nocapture` to compare.
+    #[test]
+    #[ignore]
+    fn bench_batched_pushes() {
+        use std::time::Instant;
+        const N: i64 = 64 * 1000;
+        let time = |batched: bool| {
+            let (next, this) = make_pipe(64);
+            let start = Instant::now();
+            let consumer = thread::spawn(move || {
+                let mut got = 0;
+                while got < N {
+                    got += if batched { pull_available(&this).len() as i64 } else { this.lock().unwrap().try_pop().is_some() as i64 };
+                }
+            });
+            if batched {
+                for chunk in 0..N / 64 {
+                    push_all(&next, (chunk * 64..(chunk + 1) * 64).map(Value::Int).collect()).unwrap();
                 }
+            } else {
+                for i in 0..N {
+                    push_to(&next, Value::Int(i)).unwrap();
+                }
+            }
+            consumer.join().unwrap();
+            start.elapsed()
+        };
+        println!("one at a time: {:?}", time(false));
+        println!("batched:       {:?}", time(true));
+    }
+    #[test]
+    fn test_range_literals_push_their_numbers() {
+        let env = initial_environment();
+        assert_eq!(eval_str("(1..4) | reduce(0, |acc, x| -> acc * 10 + x)", env.clone()).unwrap(), Value::Int(123));
+        assert_eq!(eval_str("(1..=4) | reduce(0, |acc, x| -> acc * 10 + x)", env.clone()).unwrap(), Value::Int(1234));
+        assert_eq!(eval_str("(1..2+2) | reduce(0, |acc, x| -> acc * 10 + x)", env.clone()).unwrap(), Value::Int(123));
+        assert_eq!(eval_str("(3..3) | reduce(0, |acc, x| -> acc + 1)", env.clone()).unwrap(), Value::Int(0));
+        assert_eq!(eval_str("(1..2*2) | reduce(0, |acc, x| -> acc * 10 + x)", env.clone()).unwrap(), Value::Int(123));
+        // A range isn't a value, it only pushes into a pipe
+        match eval_str("x := 1..4", env.clone()) {
+            Err(Error::RangeOutsidePipe(_)) => {},
+            r => panic!("expected RangeOutsidePipe, got {:?}", r),
+        }
+        // Rather than quietly piping 4 into reduce
+        match eval_source("1..4 | reduce(0, |acc, x| -> acc + x)", env, detached_consumer(), detached_producer()) {
+            Err(Error::SyntaxError(_)) => {},
+            r => panic!("expected a SyntaxError, got {:?}", r),
+        }
+    }
+    fn collect_pipe(src: &str) -> Vec<Value> {
+        let (_, this) = make_pipe(1);
+        let (next, out) = make_pipe(64);
+        eval(&parser::parse_Expr(src).unwrap(), initial_environment(), this, next).unwrap();
+        let out = out.lock().unwrap();
+        let mut values = vec![];
+        while let Some(v) = out.try_pop() {
+            values.push(v);
+        }
+        values
+    }
+    #[test]
+    fn test_range() {
+        assert_eq!(collect_pipe("range(3)"), vec![Value::Int(0), Value::Int(1), Value::Int(2)]);
+        assert_eq!(collect_pipe("range(2, 5)"), vec![Value::Int(2), Value::Int(3), Value::Int(4)]);
+        assert_eq!(collect_pipe("range(0, 10, 4)"), vec![Value::Int(0), Value::Int(4), Value::Int(8)]);
+        assert_eq!(collect_pipe("range(3, 0, -1)"), vec![Value::Int(3), Value::Int(2), Value::Int(1)]);
+        assert_eq!(collect_pipe("range(0, 1, 0.5)"), vec![Value::Number(0.0), Value::Number(0.5)]);
+        // empty ranges push nothing
+        assert_eq!(collect_pipe("range(0)"), vec![]);
+        assert_eq!(collect_pipe("range(5, 2)"), vec![]);
+        assert_eq!(collect_pipe("range(2, 5, -1)"), vec![]);
+        let env = initial_environment();
+        match eval_str("range(0, 5, 0)", env.clone()) {
+            Err(Error::InvalidTypes(_)) => {},
+            r => panic!("expected InvalidTypes, got {:?}", r),
+        }
+        match eval_str("range()", env) {
+            Err(Error::ArityOutOfRange { min: 1, max: 3, got: 0, .. }) => {},
+            r => panic!("expected ArityOutOfRange, got {:?}", r),
+        }
+    }
+    #[test]
+    fn test_custom_pipe_func() {
+        let env = initial_environment();
+        let double = pipe_prim!(|_, this: Arc<Mutex<queue::Consumer<Value>>>, next: Arc<Mutex<queue::Producer<Value>>>| {
+            while let Some(x) = pull_from(&this) {
+                push_to(&next, operations::times(&x, &Value::Int(2))?)?;
+            }
+            Ok(Value::Number(0.0))
+        });
+        env.lock().unwrap().borrow_mut().set(s!("double"), Some(double));
+        assert_eq!(eval_str("range(4) | double() | reduce(0, |a, b| -> a + b)", env).unwrap(), Value::Int(12));
+    }
+    #[test]
+    fn test_pull_after_the_pipe_is_finished() {
+        let env = initial_environment();
+        assert_eq!(eval_str("pull", env.clone()).unwrap(), Value::FinishedPipe);
+        assert_eq!(eval_str("{push 1} | {pull; pull; pull; pull}", env.clone()).unwrap(), Value::FinishedPipe);
+        assert_eq!(eval_str("{push 1} | {a := pull; pull; pull; a}", env).unwrap(), Value::Int(1));
+    }
+    #[test]
+    fn test_push_outside_a_pipeline() {
+        let env = initial_environment();
+        match eval_str("{push 1; push 2}", env.clone()) {
+            Err(Error::PushedToNone) => {},
+            r => panic!("expected PushedToNone, got {:?}", r),
+        }
+        match eval_str("range(3)", env.clone()) {
+            Err(Error::PushedToNone) => {},
+            r => panic!("expected PushedToNone, got {:?}", r),
+        }
+        // inside a pipeline the same pushes are fine
+        assert_eq!(eval_str("{push 1; push 2} | reduce(0, |a, b| -> a + b)", env).unwrap(), Value::Int(3));
+    }
+    #[test]
+    fn test_pipe_stages() {
+        assert_eq!(collect_pipe("range(5) | map(x -> x * x)"),
+                   vec![Value::Int(0), Value::Int(1), Value::Int(4), Value::Int(9), Value::Int(16)]);
+        assert_eq!(collect_pipe("range(10) | filter(x -> x % 3 = 0)"),
+                   vec![Value::Int(0), Value::Int(3), Value::Int(6), Value::Int(9)]);
+        assert_eq!(collect_pipe("range(3) | foreach(x -> push x * 10)"),
+                   vec![Value::Int(0), Value::Int(10), Value::Int(20)]);
+        assert_eq!(collect_pipe("range(0) | map(x -> x * x)"), vec![]);
+        let env = initial_environment();
+        assert_eq!(eval_str("range(5) | map(x -> x * x) | reduce(0, |a, b| -> a + b)", env.clone()).unwrap(), Value::Int(30));
+        match eval_str("range(3) | map(x -> x + 'a')", env.clone()) {
+            Err(Error::InvalidTypes(_)) => {},
+            r => panic!("expected InvalidTypes, got {:?}", r),
+        }
+        match eval_str("range(3) | map(1)", env) {
+            Err(Error::InvalidTypes(_)) => {},
+            r => panic!("expected InvalidTypes, got {:?}", r),
+        }
+    }
+    #[test]
+    fn test_try_finally() {
+        let env = initial_environment();
+        assert_eq!(eval_str("try 1 + 1 finally ran := 'success'", env.clone()).unwrap(), Value::Int(2));
+        assert_eq!(eval_str("ran", env.clone()).unwrap(), Value::Str(s!("success")));
+        match eval_str("try 1 + 'a' finally ran := 'error'", env.clone()) {
+            Err(Error::InvalidTypes(_)) => {},
+            r => panic!("expected InvalidTypes, got {:?}", r),
+        }
+        assert_eq!(eval_str("ran", env.clone()).unwrap(), Value::Str(s!("error")));
+        load_module_into_env("early() => {
+            ran := false;
+            f := || -> try {return 1; 2} finally ran := true;
+            result := f();
+            if ran then result + 10 else result
+        }", env.clone(), ".").unwrap();
+        assert_eq!(eval_str("early()", env.clone()).unwrap(), Value::Int(11));
+        let loop_cleanups = "{i := 0; cleanups := 0; while true do try {i += 1; if i = 3 then break else continue} finally cleanups += 1; cleanups}";
+        assert_eq!(eval_str(loop_cleanups, env).unwrap(), Value::Int(3));
+    }
+    #[test]
+    fn test_break_and_continue() {
+        let env = initial_environment();
+        let sum_until_five = "{i := 0; total := 0; while true do {i := i + 1; if i > 5 then break else 0; total := total + i}; total}";
+        assert_eq!(eval_str(sum_until_five, env.clone()).unwrap(), Value::Int(15));
+        let sum_odds = "{i := 0; total := 0; while i < 10 do {i := i + 1; if i % 2 = 0 then continue else 0; total := total + i}; total}";
+        assert_eq!(eval_str(sum_odds, env).unwrap(), Value::Int(25));
+    }
+    #[test]
+    fn test_do_while() {
+        let env = initial_environment();
+        // The body runs once even though the condition is false from the start
+        assert_eq!(eval_str("{n := 0; do n += 1 while false; n}", env.clone()).unwrap(), Value::Int(1));
+        assert_eq!(eval_str("{n := 0; do n += 1 while n < 5; n}", env.clone()).unwrap(), Value::Int(5));
+        assert_eq!(eval_str("{n := 0; do {n += 1; if n = 3 then break else 0} while true; n}", env.clone()).unwrap(), Value::Int(3));
+        let sum_odds = "{i := 0; total := 0; do {i := i + 1; if i % 2 = 0 then continue else 0; total := total + i} while i < 10; total}";
+        assert_eq!(eval_str(sum_odds, env).unwrap(), Value::Int(25));
+    }
+    #[test]
+    fn test_break_outside_loop_is_an_error() {
+        let env = initial_environment();
+        load_module_into_env("stop() => break", env.clone(), ".").unwrap();
+        match eval_str("while true do stop()", env) {
+            Err(Error::LoopControlOutsideLoop(_)) => {},
+            r => panic!("expected LoopControlOutsideLoop, got {:?}", r),
+        }
+    }
+    #[test]
+    fn test_closures_share_captured_variables() {
+        let env = initial_environment();
+        load_module_into_env("make_counter() => {
+            count := 0;
+            || -> {count += 1; count}
+        }", env.clone(), ".").unwrap();
+        eval_str("counter := make_counter()", env.clone()).unwrap();
+        eval_str("other := make_counter()", env.clone()).unwrap();
+        assert_eq!(eval_str("counter()", env.clone()).unwrap(), Value::Int(1));
+        assert_eq!(eval_str("counter()", env.clone()).unwrap(), Value::Int(2));
+        assert_eq!(eval_str("counter()", env.clone()).unwrap(), Value::Int(3));
+        // Each call to make_counter captures its own count.
+        assert_eq!(eval_str("other()", env).unwrap(), Value::Int(1));
+    }
+    #[test]
+    fn test_functions_do_not_rebind_globals() {
+        let env = initial_environment();
+        load_module_into_env("set_x() => {x := 5; x}", env.clone(), ".").unwrap();
+        eval_str("x := 1", env.clone()).unwrap();
+        assert_eq!(eval_str("set_x()", env.clone()).unwrap(), Value::Number(5.0));
+        assert_eq!(eval_str("x", env).unwrap(), Value::Number(1.0));
+    }
+    #[test]
+    fn test_functions_are_equal_to_themselves() {
+        let env = initial_environment();
+        load_module_into_env("square(x) => x * x\ncube(x) => x * x * x", env.clone(), ".").unwrap();
+        assert_eq!(eval_str("square = square", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("square = cube", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("print = print", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("print = len", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("map = map", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("{f := square; f = square}", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("square = 1", env.clone()).unwrap(), Value::Bool(false));
+        // Closures with the same code are only equal if they were made in the same scope
+        assert_eq!(eval_str("{f := || -> 1; g := || -> 1; f = g}", env.clone()).unwrap(), Value::Bool(true));
+        load_module_into_env("make() => || -> 1", env.clone(), ".").unwrap();
+        assert_eq!(eval_str("make() = make()", env).unwrap(), Value::Bool(false));
+    }
+    #[test]
+    fn test_assert() {
+        let env = initial_environment();
+        assert_eq!(eval_str("assert(1 < 2)", env.clone()).unwrap(), Value::Number(0.0));
+        assert_eq!(eval_str("assert(1 < 2, 'maths is broken')", env.clone()).unwrap(), Value::Number(0.0));
+        match eval_str("assert(1 > 2)", env.clone()) {
+            Err(Error::AssertionFailed(msg)) => assert_eq!(msg, "assertion failed"),
+            r => panic!("expected AssertionFailed, got {:?}", r),
+        }
+        // The error stops the rest of the block
+        match eval_str("{assert(false, 'stop here'); print('not reached')}", env.clone()) {
+            Err(Error::AssertionFailed(msg)) => assert_eq!(msg, "stop here"),
+            r => panic!("expected AssertionFailed, got {:?}", r),
+        }
+        match eval_str("assert()", env) {
+            Err(Error::ArityOutOfRange { name, min: 1, max: 2, got: 0 }) => assert_eq!(name, "assert"),
+            r => panic!("expected ArityOutOfRange, got {:?}", r),
+        }
+    }
+    #[test]
+    fn test_eval_string() {
+        let env = initial_environment();
+        assert_eq!(eval_str("eval_string('1 + 2')", env.clone()).unwrap(), Value::Int(3));
+        // It can see and change the program's variables
+        eval_str("x := 5", env.clone()).unwrap();
+        assert_eq!(eval_str("eval_string('x * 2')", env.clone()).unwrap(), Value::Int(10));
+        eval_str("eval_string('y := x + 1')", env.clone()).unwrap();
+        assert_eq!(eval_str("y", env.clone()).unwrap(), Value::Int(6));
+        match eval_str("eval_string('1 +')", env.clone()) {
+            Err(Error::SyntaxError(_)) => {},
+            r => panic!("expected a SyntaxError, got {:?}", r),
+        }
+        match eval_str("eval_string('1 + true')", env) {
+            Err(Error::InvalidTypes(_)) => {},
+            r => panic!("expected InvalidTypes, got {:?}", r),
+        }
+    }
+    #[test]
+    fn test_sandboxes_are_isolated() {
+        let a = sandbox();
+        let b = sandbox();
+        assert_eq!(a.run("x := 1").unwrap(), Value::Number(0.0));
+        a.run("double(n) => n * 2").unwrap();
+        assert_eq!(a.run("double(x)").unwrap(), Value::Int(2));
+        match b.run("x") {
+            Err(Error::UndefinedName(_)) => {},
+            r => panic!("expected UndefinedName, got {:?}", r),
+        }
+        assert!(b.run("double(1)").is_err());
+        match b.run("1 +") {
+            Err(Error::SyntaxError(_)) => {},
+            r => panic!("expected a SyntaxError, got {:?}", r),
+        }
+        // The builtins are still there
+        assert_eq!(b.run("len('abc')").unwrap(), Value::Int(3));
+    }
+    #[test]
+    fn test_sandboxes_cant_reach_outside() {
+        let sb = sandbox();
+        for name in SANDBOXED_BUILTINS {
+            match sb.run(&format!("{}('x')", name)) {
+                Err(Error::UndefinedName(_)) => {},
+                r => panic!("expected {} to be undefined, got {:?}", name, r),
+            }
+        }
+        match sb.run("use 'examples/lib.nemo'") {
+            Err(Error::InvalidArgument(_)) => {},
+            r => panic!("expected an InvalidArgument, got {:?}", r),
+        }
+        // Deep recursion hits the call depth limit instead of the stack's end
+        sb.run("forever(n) => 1 + forever(n + 1)").unwrap();
+        match sb.run("forever(0)") {
+            Err(Error::RecursionLimit(limit)) => assert_eq!(limit, calls_that_fit(SANDBOX_STACK_SIZE)),
+            r => panic!("expected a RecursionLimit, got {:?}", r),
+        }
+    }
+    #[test]
+    fn test_a_panic_in_a_sandbox_is_an_error() {
+        let sb = sandbox();
+        {
+            let lock = sb.env.lock().unwrap();
+            lock.borrow_mut().set(s!("boom"), Some(prim!(|_| -> Value { panic!("boom went off") })));
+        }
+        match sb.run("boom()") {
+            Err(Error::Panicked(message)) => assert_eq!(message, "boom went off"),
+            r => panic!("expected Panicked, got {:?}", r),
+        }
+        // The sandbox can still be used afterwards
+        assert_eq!(sb.run("1 + 1").unwrap(), Value::Int(2));
+    }
+    #[test]
+    fn test_math() {
+        let env = initial_environment();
+        assert_eq!(eval_str("math.sqrt(9)", env.clone()).unwrap(), Value::Number(3.0));
+        assert_eq!(eval_str("math.floor(2.5)", env.clone()).unwrap(), Value::Number(2.0));
+        match eval_str("math.sqrt('x')", env.clone()) {
+            Err(Error::InvalidTypes(_)) => {},
+            r => panic!("expected InvalidTypes, got {:?}", r),
+        }
+        match eval_str("math.floor()", env) {
+            Err(Error::ArityMismatch { name, got: 0, .. }) => assert_eq!(name, "math.floor"),
+            r => panic!("expected ArityMismatch, got {:?}", r),
+        }
+    }
+    #[test]
+    fn test_arity_mismatch() {
+        let env = initial_environment();
+        load_module_into_env("add(x, y) => x + y", env.clone(), ".").unwrap();
+        assert_eq!(eval_str("add(1, 2)", env.clone()).unwrap(), Value::Int(3));
+        match eval_str("add(1)", env.clone()) {
+            Err(Error::ArityMismatch { name, expected: 2, got: 1 }) => assert_eq!(name, "add"),
+            r => panic!("expected ArityMismatch, got {:?}", r),
+        }
+        match eval_str("add(1, 2, 3)", env.clone()) {
+            Err(Error::ArityMismatch { expected: 2, got: 3, .. }) => {},
+            r => panic!("expected ArityMismatch, got {:?}", r),
+        }
+        match eval_str("(x -> x)()", env) {
+            Err(Error::ArityMismatch { expected: 1, got: 0, .. }) => {},
+            r => panic!("expected ArityMismatch, got {:?}", r),
+        }
+    }
+    #[test]
+    fn test_match() {
+        let env = initial_environment();
+        load_module_into_env("name(n) => match n { 1 => 'one', 2 => 'two', -1 => 'minus one', _ => 'other' }
+        is_yes(s) => match s { 'yes' => true, 'y' => true, _ => false }", env.clone(), ".").unwrap();
+        assert_eq!(eval_str("name(1)", env.clone()).unwrap(), Value::Str(s!("one")));
+        assert_eq!(eval_str("name(2)", env.clone()).unwrap(), Value::Str(s!("two")));
+        assert_eq!(eval_str("name(-1)", env.clone()).unwrap(), Value::Str(s!("minus one")));
+        assert_eq!(eval_str("name(3)", env.clone()).unwrap(), Value::Str(s!("other")));
+        assert_eq!(eval_str("is_yes('y')", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("is_yes('no')", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("match 1 = 1 { false => 0, true => 1 }", env.clone()).unwrap(), Value::Int(1));
+        match eval_str("match 5 { 1 => 1 }", env) {
+            Err(Error::NoMatchingPattern(_)) => {},
+            r => panic!("expected NoMatchingPattern, got {:?}", r),
+        }
+    }
+    #[test]
+    fn test_match_evaluates_scrutinee_once() {
+        let env = initial_environment();
+        load_module_into_env("make_counter() => {count := 0; || -> {count += 1; count}}", env.clone(), ".").unwrap();
+        eval_str("next_number := make_counter()", env.clone()).unwrap();
+        assert_eq!(eval_str("match next_number() { 2 => 'second', 1 => 'first', _ => 'later' }", env.clone()).unwrap(), Value::Str(s!("first")));
+        assert_eq!(eval_str("next_number()", env).unwrap(), Value::Int(2));
+    }
+    #[test]
+    fn test_normalize() {
+        let env = initial_environment();
+        // 'e' followed by a combining acute accent, and the precomposed 'é'
+        let decomposed = "'e\u{301}'";
+        let composed = "'\u{e9}'";
+        assert_eq!(eval_str(&format!("{} = {}", decomposed, composed), env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str(&format!("normalize({}) = normalize({})", decomposed, composed), env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str(&format!("normalize({}, 'NFD')", composed), env.clone()).unwrap(), Value::Str(s!("e\u{301}")));
+        assert_eq!(eval_str(&format!("normalize({}, 'NFC')", decomposed), env.clone()).unwrap(), Value::Str(s!("\u{e9}")));
+        for src in &["normalize(5)", "normalize('a', 5)"] {
+            match eval_str(src, env.clone()) {
+                Err(Error::InvalidTypes(_)) => {},
+                res => panic!("expected InvalidTypes from {}, got {:?}", src, res),
             }
-            _ => Err(Error::InvalidTypes(format!("{:?} is not indexable", obj)))
+        }
+        match eval_str("normalize()", env.clone()) {
+            Err(Error::ArityOutOfRange { min: 1, max: 2, got: 0, .. }) => {},
+            res => panic!("expected ArityOutOfRange, got {:?}", res),
+        }
+        assert!(eval_str("normalize('a', 'NFX')", env).is_err());
+    }
+    #[test]
+    fn test_starts_with_and_ends_with() {
+        let env = initial_environment();
+        assert_eq!(eval_str("starts_with('nemo', 'ne')", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("starts_with('nemo', 'mo')", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("starts_with('nemo', '')", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("starts_with('ne', 'nemo')", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("ends_with('nemo', 'mo')", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("ends_with('nemo', 'ne')", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("ends_with('nemo', '')", env.clone()).unwrap(), Value::Bool(true));
+        // Whole graphemes are compared, so a base letter doesn't match the letter with its accent
+        assert_eq!(eval_str("starts_with('e\u{301}t\u{e9}', 'e')", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("starts_with('e\u{301}t\u{e9}', 'e\u{301}')", env).unwrap(), Value::Bool(true));
+    }
+    #[test]
+    fn test_starts_with_needs_strings() {
+        let env = initial_environment();
+        for src in &["starts_with(1, 'a')", "ends_with('a', true)"] {
+            match eval_str(src, env.clone()) {
+                Err(Error::InvalidTypes(_)) => {},
+                res => panic!("expected InvalidTypes from {}, got {:?}", src, res),
+            }
+        }
+        match eval_str("starts_with('a')", env) {
+            Err(Error::ArityMismatch { expected: 2, got: 1, .. }) => {},
+            res => panic!("expected an ArityMismatch, got {:?}", res),
+        }
+    }
+    #[test]
+    fn test_modulo_follows_the_divisor_sign() {
+        let env = initial_environment();
+        assert_eq!(eval_str("-1 % 3", env.clone()).unwrap(), Value::Int(-1));
+        assert_eq!(eval_str("modulo(-1, 3)", env.clone()).unwrap(), Value::Int(2));
+        assert_eq!(eval_str("1 % -3", env.clone()).unwrap(), Value::Int(1));
+        assert_eq!(eval_str("modulo(1, -3)", env.clone()).unwrap(), Value::Int(-2));
+        assert_eq!(eval_str("modulo(-7, -3)", env.clone()).unwrap(), Value::Int(-1));
+        assert_eq!(eval_str("modulo(7, 3)", env.clone()).unwrap(), Value::Int(1));
+        assert_eq!(eval_str("modulo(-6, 3)", env.clone()).unwrap(), Value::Int(0));
+        match eval_str("modulo('a', 3)", env.clone()) {
+            Err(Error::InvalidTypes(_)) => {},
+            res => panic!("expected InvalidTypes, got {:?}", res),
+        }
+        match eval_str("modulo(1)", env) {
+            Err(Error::ArityMismatch { expected: 2, got: 1, .. }) => {},
+            res => panic!("expected an ArityMismatch, got {:?}", res),
+        }
+    }
+    #[test]
+    fn test_errors_shorten_huge_values() {
+        let env = initial_environment();
+        let huge = "a".repeat(100000);
+        match eval_str(&format!("'{}' + 1", huge), env) {
+            Err(Error::InvalidTypes(msg)) => {
+                assert!(msg.len() < 200);
+                assert!(msg.contains("aaa..."));
+            },
+            r => panic!("expected InvalidTypes, got {:?}", r),
+        }
+        assert_eq!(display_for_error(&Value::Str(s!("short"))), "'short'");
+    }
+    #[test]
+    fn test_int_and_float_arithmetic() {
+        let env = initial_environment();
+        match eval_str("7 // 2", env.clone()).unwrap() {
+            Value::Int(3) => {},
+            v => panic!("expected Int(3), got {:?}", v),
+        }
+        match eval_str("7 / 2", env.clone()).unwrap() {
+            Value::Number(n) => assert_eq!(n, 3.5),
+            v => panic!("expected Number(3.5), got {:?}", v),
+        }
+        assert_eq!(eval_str("-7 // 2", env.clone()).unwrap(), Value::Int(-4));
+        assert_eq!(eval_str("7.5 // 2", env.clone()).unwrap(), Value::Number(3.0));
+        match eval_str("2 + 3 * 4", env.clone()).unwrap() {
+            Value::Int(14) => {},
+            v => panic!("expected Int(14), got {:?}", v),
+        }
+        match eval_str("2 + 0.5", env.clone()).unwrap() {
+            Value::Number(n) => assert_eq!(n, 2.5),
+            v => panic!("expected Number(2.5), got {:?}", v),
+        }
+        assert_eq!(eval_str("7 % 3", env.clone()).unwrap(), Value::Int(1));
+        assert_eq!(eval_str("1 = 1.0", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("2 > 1.5", env.clone()).unwrap(), Value::Bool(true));
+        // Overflowing an Int falls back to floats instead of panicking
+        match eval_str("9223372036854775807 + 1", env.clone()).unwrap() {
+            Value::Number(_) => {},
+            v => panic!("expected a Number, got {:?}", v),
+        }
+        match eval_str("1 // 0", env).unwrap() {
+            Value::Number(n) => assert!(n.is_infinite()),
+            v => panic!("expected a Number, got {:?}", v),
+        }
+    }
+    #[test]
+    fn test_display_for_repl() {
+        assert_eq!(display_for_repl(&Value::Number(2.0), false), "2");
+        assert_eq!(display_for_repl(&Value::Number(2.0), true), "2.0");
+        assert_eq!(display_for_repl(&Value::Number(-3.0), true), "-3.0");
+        assert_eq!(display_for_repl(&Value::Number(2.5), true), "2.5");
+        assert_eq!(display_for_repl(&Value::Int(2), true), "2");
+        assert_eq!(display_for_repl(&Value::Number(1.0 / 0.0), true), "inf");
+        assert_eq!(display_for_repl(&Value::Str(s!("2")), true), "'2'");
+    }
+    #[test]
+    fn test_strip_repl_semicolon() {
+        assert_eq!(strip_repl_semicolon("print(1)"), ("print(1)", false));
+        assert_eq!(strip_repl_semicolon("print(1);"), ("print(1)", true));
+        assert_eq!(strip_repl_semicolon("x := 5;  "), ("x := 5", true));
+        assert_eq!(strip_repl_semicolon("{1; 2}"), ("{1; 2}", false));
+        assert_eq!(strip_repl_semicolon("';'"), ("';'", false));
+    }
+    #[test]
+    fn test_repl_setting() {
+        ::std::env::remove_var("NEMO_PROMPT");
+        assert_eq!(repl_setting("NEMO_PROMPT", "> "), "> ");
+        ::std::env::set_var("NEMO_PROMPT", "nemo$ ");
+        assert_eq!(repl_setting("NEMO_PROMPT", "> "), "nemo$ ");
+        ::std::env::set_var("NEMO_PROMPT", "");
+        assert_eq!(repl_setting("NEMO_PROMPT", "> "), "");
+        ::std::env::remove_var("NEMO_PROMPT");
+    }
+    #[test]
+    fn test_format_number() {
+        assert_eq!(format_number(1.23456, "fixed", 2), Some(s!("1.23")));
+        assert_eq!(format_number(2.0, "fixed", 3), Some(s!("2.000")));
+        assert_eq!(format_number(1234.5, "sci", 2), Some(s!("1.23e3")));
+        assert_eq!(format_number(0.00042, "sci", 1), Some(s!("4.2e-4")));
+        assert_eq!(format_number(1234567.0, "grouped", 0), Some(s!("1,234,567")));
+        assert_eq!(format_number(-1234.5678, "grouped", 2), Some(s!("-1,234.57")));
+        assert_eq!(format_number(999.0, "grouped", 0), Some(s!("999")));
+        assert_eq!(format_number(-0.001, "grouped", 0), Some(s!("0")));
+        assert_eq!(format_number(1.0, "roman", 0), None);
+        let env = initial_environment();
+        assert_eq!(eval_str("format_number(7 / 3)", env.clone()).unwrap(), Value::Str(s!("2.33")));
+        assert_eq!(eval_str("format_number(1000000, 'grouped')", env.clone()).unwrap(), Value::Str(s!("1,000,000")));
+        assert_eq!(eval_str("format_number(12000, 'sci', 1)", env.clone()).unwrap(), Value::Str(s!("1.2e4")));
+        match eval_str("format_number()", env) {
+            Err(Error::ArityOutOfRange { min: 1, max: 3, got: 0, .. }) => {},
+            res => panic!("expected ArityOutOfRange, got {:?}", res),
+        }
+    }
+    #[test]
+    fn test_format_number_with_decimals() {
+        assert_eq!(round_to_decimals(0.1 + 0.2, 2), "0.30");
+        assert_eq!(round_to_decimals(2.675, 1), "2.7");
+        assert_eq!(round_to_decimals(2.5, 0), "3");
+        assert_eq!(round_to_decimals(-2.5, 0), "-3");
+        assert_eq!(round_to_decimals(3.0, 3), "3.000");
+        assert_eq!(round_to_decimals(1234.5, -2), "1200");
+        assert_eq!(round_to_decimals(1250.0, -2), "1300");
+        assert_eq!(round_to_decimals(-40.0, -2), "0");
+        let env = initial_environment();
+        assert_eq!(eval_str("format_number(0.1 + 0.2, 2)", env.clone()).unwrap(), Value::Str(s!("0.30")));
+        assert_eq!(eval_str("format_number(7, 2)", env.clone()).unwrap(), Value::Str(s!("7.00")));
+        assert_eq!(eval_str("format_number(98765, -3)", env.clone()).unwrap(), Value::Str(s!("99000")));
+        assert_eq!(eval_str("format_number(7, 2.0)", env.clone()).unwrap(), Value::Str(s!("7.00")));
+        assert_eq!(eval_str("format_number(98765, -9223372036854775807)", env.clone()).unwrap(), Value::Str(s!("0")));
+        for src in &["format_number()", "format_number('7')", "format_number(7, 2.5)", "format_number(7, true)",
+                     "format_number(7, 9223372036854775807)", "format_number(7, 'fixed', -1)", "format_number(7, 'roman')"] {
+            assert!(eval_str(src, env.clone()).is_err(), "{} should be an error", src);
+        }
+    }
+    #[test]
+    fn test_failing_producer_ends_the_pipe() {
+        let env = initial_environment();
+        let results = vec![
+            eval_str("undefined_thing() | reduce(0, |a, x| -> a + x)", env.clone()),
+            eval_str("{push 1; push 2; undefined_thing()} | reduce(0, |a, x| -> a + x)", env.clone()),
+            eval_str("{push 1; undefined_thing()} | map(x -> x * 2) | reduce(0, |a, x| -> a + x)", env.clone()),
+        ];
+        for result in results {
+            match result {
+                Err(Error::UndefinedName(_)) => {},
+                res => panic!("expected the producer's UndefinedName, got {:?}", res),
+            }
+        }
+        // a consumer that stops early still gets its own result
+        assert_eq!(eval_str("range(1000) | {pull; pull}", env).unwrap(), Value::Int(1));
+    }
+    #[test]
+    fn test_reduce() {
+        let env = initial_environment();
+        assert_eq!(eval_str("range(10) | reduce(0, |acc, x| -> acc + x)", env.clone()).unwrap(), Value::Int(45));
+        assert_eq!(eval_str("range(1, 4) | reduce('', |acc, x| -> acc + \"${x}\")", env.clone()).unwrap(), Value::Str(s!("123")));
+        assert_eq!(eval_str("range(0) | reduce(7, |acc, x| -> acc + x)", env.clone()).unwrap(), Value::Int(7));
+        assert!(eval_str("range(3) | reduce(|acc, x| -> acc + x)", env).is_err());
+    }
+    #[test]
+    fn test_print_and_write_output() {
+        let args = vec![Value::Str(s!("a")), Value::Int(1), Value::Bool(true)];
+        let mut out = vec![];
+        print_values(&mut out, &args);
+        assert_eq!(out, b"a 1 true \n");
+        let mut out = vec![];
+        write_values(&mut out, &args);
+        write_values(&mut out, &[Value::Str(s!("!"))]);
+        assert_eq!(out, b"a1true!");
+        let mut out = vec![];
+        write_values(&mut out, &[]);
+        assert_eq!(out, b"");
+    }
+    // A Write whose contents can still be read after it has been given away
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    #[test]
+    fn test_program_with_scripted_io() {
+        let output = SharedBuffer(Arc::new(Mutex::new(vec![])));
+        let io = Io::new(io::Cursor::new(b"Bob\n42\n".to_vec()), output.clone(), io::sink());
+        let env = initial_environment_with_io(io);
+        load_module_into_env("main() => {name := input(); age := input(); write('Hi, ', name); print('!'); print(age)}", env.clone(), ".").unwrap();
+        eval_str("main()", env).unwrap();
+        assert_eq!(&*output.0.lock().unwrap(), b"Hi, Bob! \n42 \n");
+    }
+    #[test]
+    fn test_inspect() {
+        let mut out = Vec::new();
+        assert_eq!(inspect(&mut out, &Value::Str(s!("nemo"))), Value::Str(s!("nemo")));
+        assert_eq!(inspect(&mut out, &Value::Int(3)), Value::Int(3));
+        assert_eq!(String::from_utf8(out).unwrap(), "'nemo'\n3\n");
+        let errors = SharedBuffer(Arc::new(Mutex::new(vec![])));
+        let output = SharedBuffer(Arc::new(Mutex::new(vec![])));
+        let env = initial_environment_with_io(Io::new(io::empty(), output.clone(), errors.clone()));
+        assert_eq!(eval_str("inspect(2) * 3", env.clone()).unwrap(), Value::Int(6));
+        assert_eq!(eval_str("range(3) | map(x -> inspect(x)) | reduce(0, |a, b| -> a + b)", env.clone()).unwrap(), Value::Int(3));
+        assert_eq!(eval_str("inspect('fish')", env).unwrap(), Value::Str(s!("fish")));
+        assert_eq!(String::from_utf8(errors.0.lock().unwrap().clone()).unwrap(), "2\n0\n1\n2\n'fish'\n");
+        assert!(output.0.lock().unwrap().is_empty());
+    }
+    #[test]
+    fn test_len() {
+        let env = initial_environment();
+        assert_eq!(eval_str("len('nemo')", env.clone()).unwrap(), Value::Int(4));
+        assert_eq!(eval_str("len('')", env.clone()).unwrap(), Value::Int(0));
+        assert_eq!(eval_str("len('🇷🇺 🇸🇹 😀')", env.clone()).unwrap(), Value::Int(5));
+        // the old attribute still works
+        assert_eq!(eval_str("'nemo'.len()", env.clone()).unwrap(), Value::Int(4));
+        for bad in &["len(1)", "len(1.5)", "len(true)", "len(print)", "len(|x| -> x)"] {
+            match eval_str(bad, env.clone()) {
+                Err(Error::InvalidTypes(_)) => {},
+                other => panic!("expected {} to be an InvalidTypes error, got {:?}", bad, other),
+            }
+        }
+        match eval_str("len('a', 'b')", env) {
+            Err(Error::ArityMismatch { expected: 1, got: 2, .. }) => {},
+            other => panic!("expected an arity mismatch, got {:?}", other),
+        }
+    }
+    #[test]
+    fn test_is_empty() {
+        let env = initial_environment();
+        assert_eq!(eval_str("is_empty('')", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("is_empty('nemo')", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("is_empty('\u{301}')", env).unwrap(), Value::Bool(false));
+    }
+    #[test]
+    fn test_is_empty_rejects_numbers() {
+        let env = initial_environment();
+        match eval_str("is_empty(0)", env.clone()) {
+            Err(Error::InvalidTypes(_)) => {},
+            res => panic!("expected InvalidTypes, got {:?}", res),
+        }
+        match eval_str("is_empty()", env) {
+            Err(Error::ArityMismatch { expected: 1, got: 0, .. }) => {},
+            res => panic!("expected an ArityMismatch, got {:?}", res),
+        }
+    }
+    #[test]
+    fn test_negation() {
+        let env = initial_environment();
+        eval_str("x := 5", env.clone()).unwrap();
+        assert_eq!(eval_str("-x", env.clone()).unwrap(), Value::Int(-5));
+        assert_eq!(eval_str("-(x + 0.5)", env.clone()).unwrap(), Value::Number(-5.5));
+        assert_eq!(eval_str("3 - -x", env.clone()).unwrap(), Value::Int(8));
+        assert_eq!(eval_str("--x", env.clone()).unwrap(), Value::Int(5));
+        match eval_str("-'nemo'", env) {
+            Err(Error::InvalidTypes(_)) => {},
+            other => panic!("expected an InvalidTypes error, got {:?}", other),
+        }
+    }
+    #[test]
+    fn test_comparing_special_floats() {
+        let env = initial_environment();
+        eval_str("{nan := 0.0 / 0.0; inf := 1.0 / 0.0}", env.clone()).unwrap();
+        assert_eq!(eval_str("0.0 = -0.0", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("nan = nan", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("nan != nan", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("inf > 1", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("-inf < -1.5", env.clone()).unwrap(), Value::Bool(true));
+        assert!(eval_str("nan > 1", env.clone()).is_err());
+        assert!(eval_str("1 < nan", env).is_err());
+    }
+    #[test]
+    fn test_nan_and_infinity_checks() {
+        let env = initial_environment();
+        eval_str("{nan := 0.0 / 0.0; inf := 1.0 / 0.0}", env.clone()).unwrap();
+        assert_eq!(eval_str("is_nan(nan)", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("is_nan(inf)", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("is_infinite(inf)", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("is_infinite(-inf)", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("is_infinite(nan)", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("is_finite(1.5)", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("is_finite(7)", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("is_finite(nan)", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("is_finite(inf)", env.clone()).unwrap(), Value::Bool(false));
+        assert!(eval_str("is_nan('nan')", env).is_err());
+    }
+    #[test]
+    fn test_string_interpolation() {
+        let env = initial_environment();
+        eval_str("{name := 'Ada'; age := 36}", env.clone()).unwrap();
+        assert_eq!(eval_str("\"hello ${name}, you are ${age} years old\"", env.clone()).unwrap(), Value::Str(s!("hello Ada, you are 36 years old")));
+        assert_eq!(eval_str("\"${age + 1}\"", env.clone()).unwrap(), Value::Str(s!("37")));
+        assert_eq!(eval_str("'fish' + ' and ' + 'chips'", env.clone()).unwrap(), Value::Str(s!("fish and chips")));
+        assert!(eval_str("'a' + 1", env).is_err());
+    }
+    #[test]
+    fn test_bitwise_operators() {
+        let env = initial_environment();
+        assert_eq!(eval_str("12 & 10", env.clone()).unwrap(), Value::Int(8));
+        assert_eq!(eval_str("12 || 10", env.clone()).unwrap(), Value::Int(14));
+        assert_eq!(eval_str("12 ^ 10", env.clone()).unwrap(), Value::Int(6));
+        assert_eq!(eval_str("1 << 4", env.clone()).unwrap(), Value::Int(16));
+        assert_eq!(eval_str("-16 >> 2", env.clone()).unwrap(), Value::Int(-4));
+        assert_eq!(eval_str("6.0 & 3", env.clone()).unwrap(), Value::Int(2));
+        assert_eq!(eval_str("true ^ false", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("true ^ true", env.clone()).unwrap(), Value::Bool(false));
+        // Shifts bind tighter than &, which binds tighter than ^ and ||
+        assert_eq!(eval_str("1 || 2 ^ 3 & 1 << 1", env.clone()).unwrap(), Value::Int(1));
+        assert!(eval_str("1.5 & 1", env.clone()).is_err());
+        assert!(eval_str("1 << 64", env.clone()).is_err());
+        assert!(eval_str("1 >> -1", env.clone()).is_err());
+        assert!(eval_str("'a' & 1", env.clone()).is_err());
+        // An empty lambda still works even though "||" is an operator now
+        assert_eq!(eval_str("{f := || -> 7; f()}", env).unwrap(), Value::Int(7));
+    }
+    #[test]
+    fn test_power() {
+        let env = initial_environment();
+        assert_eq!(eval_str("2 ** 3 ** 2", env.clone()).unwrap(), Value::Int(512));
+        assert_eq!(eval_str("2 * 3 ** 2", env.clone()).unwrap(), Value::Int(18));
+        assert_eq!(eval_str("2 ** -1", env.clone()).unwrap(), Value::Number(0.5));
+        assert_eq!(eval_str("4 ** 0.5", env.clone()).unwrap(), Value::Number(2.0));
+        assert_eq!(eval_str("-2 ** 2", env.clone()).unwrap(), Value::Int(-4));
+        assert_eq!(eval_str("3 ** 0", env.clone()).unwrap(), Value::Int(1));
+        assert_eq!(eval_str("(-2) ** 63", env.clone()).unwrap(), Value::Int(i64::min_value()));
+        assert_eq!(eval_str("(-1) ** 9223372036854775807", env.clone()).unwrap(), Value::Int(-1));
+        match eval_str("2 ** 63", env.clone()).unwrap() {
+            Value::Number(_) => {},
+            v => panic!("expected a Number, got {:?}", v),
+        }
+        match eval_str("10 ** 100", env).unwrap() {
+            Value::Number(_) => {},
+            v => panic!("expected a Number, got {:?}", v),
+        }
+    }
+    #[test]
+    fn test_lookup_in_a_deep_environment() {
+        let root = Arc::new(Mutex::new(RefCell::new(Environment::extend(vec![(s!("root"), Value::Int(1))], None))));
+        let mut env = root;
+        for i in 0..10000 {
+            env = Arc::new(Mutex::new(RefCell::new(Environment::extend(vec![(format!("x{}", i), Value::Int(i))], Some(env)))));
+        }
+        let lock = env.lock().unwrap();
+        assert_eq!(lock.borrow().lookup("root"), Some(Some(Value::Int(1))));
+        assert_eq!(lock.borrow().lookup("x5000"), Some(Some(Value::Int(5000))));
+        assert_eq!(lock.borrow().lookup("missing"), None);
+        assert_eq!(lock.borrow().depth(), 10001);
+        let names = lock.borrow().names();
+        assert_eq!(names.len(), 10001);
+        assert!(names.binary_search(&s!("x0")).is_ok());
+        // := from the innermost frame updates the frame far up that binds the name
+        lock.borrow_mut().assign(s!("x0"), Value::Int(-1));
+        assert_eq!(lock.borrow().lookup("x0"), Some(Some(Value::Int(-1))));
+        assert_eq!(lock.borrow().frame_names(), vec![s!("x9999")]);
+    }
+    #[test]
+    fn test_dropping_a_poisoned_frame() {
+        let root = Arc::new(Mutex::new(RefCell::new(Environment::extend(vec![], None))));
+        let frame = Arc::new(Mutex::new(RefCell::new(Environment::extend(vec![], Some(root)))));
+        let poisoner = frame.clone();
+        assert!(thread::spawn(move || {
+            let _lock = poisoner.lock().unwrap();
+            panic!("poisoning the frame");
+        }).join().is_err());
+        assert!(frame.is_poisoned());
+        // Dropping the only other reference unlinks the poisoned frame without panicking
+        drop(Environment::extend(vec![], Some(frame)));
+    }
+    #[test]
+    fn test_env_stats() {
+        let env = initial_environment();
+        assert_eq!(env_stats(&env), EnvStats { names: 0, functions: 0, values: 0, depth: 1 });
+        load_module_into_env("square(x) => x * x", env.clone(), ".").unwrap();
+        eval_str("x := 5", env.clone()).unwrap();
+        eval_str("inc := |n| -> n + 1", env.clone()).unwrap();
+        assert_eq!(env_stats(&env), EnvStats { names: 3, functions: 2, values: 1, depth: 1 });
+        let inner = Arc::new(Mutex::new(RefCell::new(Environment::extend(vec![(s!("y"), Value::Int(1))], Some(env)))));
+        assert_eq!(env_stats(&inner), EnvStats { names: 4, functions: 2, values: 2, depth: 2 });
+    }
+    #[test]
+    fn test_environment_names() {
+        let env = initial_environment();
+        eval_str("a := 1", env.clone()).unwrap();
+        eval_str("b := 'two'", env.clone()).unwrap();
+        let inner = Environment::extend(vec![(s!("c"), Value::Int(3)), (s!("a"), Value::Int(4))], Some(env.clone()));
+        assert_eq!(inner.frame_names(), vec![s!("a"), s!("c")]);
+        assert_eq!(inner.bindings(), vec![(s!("a"), Value::Int(4)), (s!("c"), Value::Int(3))]);
+        let names = inner.names();
+        for name in &["a", "b", "c", "print"] {
+            assert!(names.contains(&s!(*name)), "{} is missing from {:?}", name, names);
+        }
+        assert_eq!(names.iter().filter(|name| *name == "a").count(), 1);
+        let lock = env.lock().unwrap();
+        assert!(!lock.borrow().frame_names().contains(&s!("c")));
+    }
+    #[test]
+    fn test_json_diagnostics() {
+        let src = "main() => 1\nf(x) => x +\n";
+        let e = parser::parse_Program(src).unwrap_err();
+        let diagnostic = parse_error_diagnostic("bad.nemo", src, &e);
+        assert_eq!(diagnostic, Diagnostic {
+            file: s!("bad.nemo"),
+            line: Some(3),
+            column: Some(1),
+            severity: "error",
+            message: s!("unexpected end of file"),
+        });
+        let runtime = diagnostic_without_place("C:\\bad.nemo", s!("UndefinedName(\"x is not defined\")\n"));
+        assert_eq!(diagnostics_to_json(&[diagnostic, runtime]),
+                   r#"[{"file":"bad.nemo","line":3,"column":1,"severity":"error","message":"unexpected end of file"},"#.to_owned() +
+                   r#"{"file":"C:\\bad.nemo","line":null,"column":null,"severity":"error","message":"UndefinedName(\"x is not defined\")\n"}]"#);
+        assert_eq!(diagnostics_to_json(&[]), "[]");
+        assert_eq!(line_and_column("ab\ncdé f", 8), (2, 5));
+    }
+    #[test]
+    fn test_string_ordering() {
+        let env = initial_environment();
+        assert_eq!(eval_str("'apple' < 'banana'", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("'b' > 'a'", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("'app' < 'apple'", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("'Zebra' < 'apple'", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("'a' > 'a'", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("'é' > 'z'", env.clone()).unwrap(), Value::Bool(true));
+        match eval_str("'1' < 2", env) {
+            Err(Error::InvalidTypes(_)) => {},
+            res => panic!("expected InvalidTypes, got {:?}", res),
+        }
+    }
+    #[test]
+    fn test_apply_user_function() {
+        let env = initial_environment();
+        load_module_into_env("add(x, y) => { if x > 100 then return 0 else 0; x + y }", env.clone(), ".").unwrap();
+        let add = eval_str("add", env.clone()).unwrap();
+        assert_eq!(apply(&add, vec![Value::Int(2), Value::Int(3)], detached_consumer(), detached_producer()).unwrap(), Value::Int(5));
+        // an early return is the function's result, not an error
+        assert_eq!(apply(&add, vec![Value::Int(200), Value::Int(3)], detached_consumer(), detached_producer()).unwrap(), Value::Int(0));
+        let offset = eval_str("{n := 10; x -> x + n}", env).unwrap();
+        assert_eq!(apply(&offset, vec![Value::Int(1)], detached_consumer(), detached_producer()).unwrap(), Value::Int(11));
+        match apply(&add, vec![Value::Int(1)], detached_consumer(), detached_producer()) {
+            Err(Error::ArityMismatch { expected: 2, got: 1, .. }) => {},
+            res => panic!("expected an ArityMismatch, got {:?}", res),
+        }
+        match apply(&Value::Int(1), vec![], detached_consumer(), detached_producer()) {
+            Err(Error::InvalidTypes(_)) => {},
+            res => panic!("expected InvalidTypes, got {:?}", res),
+        }
+    }
+    #[test]
+    fn test_default_arguments() {
+        let env = initial_environment();
+        load_module_into_env("add(x, y=1) => x + y\nscaled(x, by=factor * 2) => x * by", env.clone(), ".").unwrap();
+        assert_eq!(eval_str("add(5)", env.clone()).unwrap(), Value::Int(6));
+        assert_eq!(eval_str("add(5, 10)", env.clone()).unwrap(), Value::Int(15));
+        // defaults are evaluated when the function is called
+        eval_str("factor := 2", env.clone()).unwrap();
+        assert_eq!(eval_str("scaled(3)", env.clone()).unwrap(), Value::Int(12));
+        eval_str("factor := 5", env.clone()).unwrap();
+        assert_eq!(eval_str("scaled(3)", env.clone()).unwrap(), Value::Int(30));
+        match eval_str("add()", env.clone()) {
+            Err(Error::ArityOutOfRange { min: 1, max: 2, got: 0, .. }) => {},
+            res => panic!("expected an ArityOutOfRange, got {:?}", res),
+        }
+        match eval_str("add(1, 2, 3)", env) {
+            Err(Error::ArityOutOfRange { min: 1, max: 2, got: 3, .. }) => {},
+            res => panic!("expected an ArityOutOfRange, got {:?}", res),
+        }
+    }
+    #[test]
+    fn test_named_arguments() {
+        let env = initial_environment();
+        load_module_into_env("point(x, y, z=0) => x * 100 + y * 10 + z", env.clone(), ".").unwrap();
+        assert_eq!(eval_str("point(x: 1, y: 2)", env.clone()).unwrap(), Value::Int(120));
+        assert_eq!(eval_str("point(y: 2, x: 1, z: 3)", env.clone()).unwrap(), Value::Int(123));
+        assert_eq!(eval_str("point(1, z: 3, y: 2)", env.clone()).unwrap(), Value::Int(123));
+        assert_eq!(eval_str("(|a, b| -> a - b)(b: 1, a: 3)", env.clone()).unwrap(), Value::Int(2));
+        for src in &["point(1, w: 2)", "point(1, x: 2)", "point(x: 1, x: 2)", "point(z: 1, x: 2)", "print(x: 1)"] {
+            match eval_str(src, env.clone()) {
+                Err(Error::InvalidArgument(_)) => {},
+                res => panic!("expected InvalidArgument from {}, got {:?}", src, res),
+            }
+        }
+    }
+    #[test]
+    fn test_random() {
+        let env = initial_environment();
+        for _ in 0..100 {
+            assert_eq!(eval_str("random_int(1, 1)", env.clone()).unwrap(), Value::Int(1));
+            match eval_str("random_int(-3, 3)", env.clone()).unwrap() {
+                Value::Int(-3..=3) => {},
+                v => panic!("expected an Int from -3 to 3, got {:?}", v),
+            }
+            match eval_str("random()", env.clone()).unwrap() {
+                Value::Number(n) => {
+                    assert!(n >= 0.0, "{} is below 0", n);
+                    assert!(n < 1.0, "{} is not below 1", n);
+                },
+                v => panic!("expected a float, got {:?}", v),
+            }
+        }
+        assert!(eval_str("random_int(3, 1)", env.clone()).is_err());
+        assert!(eval_str("random_int(1.5, 2)", env.clone()).is_err());
+        assert!(eval_str("seed('a')", env.clone()).is_err());
+        let run = || {
+            eval_str("seed(42)", env.clone()).unwrap();
+            (0..5).map(|_| eval_str("random_int(0, 1000000)", env.clone()).unwrap()).collect::<Vec<Value>>()
+        };
+        let first = run();
+        assert_eq!(first, run());
+        assert!(first.iter().any(|v| *v != first[0]));
+    }
+    #[test]
+    fn test_now_and_sleep() {
+        let env = initial_environment();
+        let elapsed = eval_str("{start := now(); sleep(50); now() - start}", env.clone()).unwrap();
+        match elapsed {
+            Value::Number(n) if n >= 0.05 => {},
+            v => panic!("expected at least 0.05 seconds to pass, got {:?}", v),
+        }
+        assert_eq!(eval_str("sleep(0.5)", env.clone()).unwrap(), Value::Number(0.0));
+        assert!(eval_str("sleep(-1)", env.clone()).is_err());
+        assert!(eval_str("sleep('soon')", env).is_err());
+    }
+    #[test]
+    fn test_time_it() {
+        let env = initial_environment();
+        match eval_str("time_it(|| -> sleep(50))", env.clone()).unwrap() {
+            Value::Number(n) => {
+                assert!(n >= 0.05, "sleeping 50 ms only took {} seconds", n);
+                assert!(n < 1.0, "sleeping 50 ms took {} seconds", n);
+            },
+            v => panic!("expected a Number, got {:?}", v),
+        }
+        assert!(eval_str("time_it(|| -> undefined_thing())", env.clone()).is_err());
+        assert!(eval_str("time_it(5)", env.clone()).is_err());
+        assert!(eval_str("time_it()", env).is_err());
+    }
+    #[test]
+    fn test_recursion_limit() {
+        // The test thread's stack isn't big enough to reach the limit
+        let deep = thread::Builder::new().stack_size(interpreter_stack_size()).spawn(|| {
+            let env = initial_environment();
+            load_module_into_env("forever(n) => 1 + forever(n + 1)\ncount(n) => if n = 0 then 0 else 1 + count(n - 1)", env.clone(), ".").unwrap();
+            let result = match eval_str("forever(0)", env.clone()) {
+                Err(Error::RecursionLimit(limit)) => Ok(limit),
+                res => Err(format!("{:?}", res)),
+            };
+            // count(n) makes n + 1 calls, so this goes exactly as deep as the
+            // limit allows, which the stack has room for in a debug build too.
+            // The depth also goes back down after the error.
+            let deepest = eval_str(&format!("count({})", DEFAULT_MAX_CALL_DEPTH - 1), env.clone()).unwrap();
+            let too_deep = match eval_str(&format!("count({})", DEFAULT_MAX_CALL_DEPTH), env) {
+                Err(Error::RecursionLimit(limit)) => Ok(limit),
+                res => Err(format!("{:?}", res)),
+            };
+            (result, deepest, too_deep)
+        }).unwrap();
+        let (result, deepest, too_deep) = deep.join().unwrap();
+        assert_eq!(result, Ok(DEFAULT_MAX_CALL_DEPTH));
+        assert_eq!(deepest, Value::Int(DEFAULT_MAX_CALL_DEPTH as i64 - 1));
+        assert_eq!(too_deep, Ok(DEFAULT_MAX_CALL_DEPTH));
+        // pipe stages have smaller stacks and a lower limit of their own
+        let env = initial_environment();
+        load_module_into_env("forever(n) => 1 + forever(n + 1)", env.clone(), ".").unwrap();
+        match eval_str("{push forever(0)} | reduce(0, |a, x| -> a + x)", env) {
+            Err(Error::RecursionLimit(limit)) => assert_eq!(limit, 496),
+            res => panic!("expected a RecursionLimit, got {:?}", res),
+        }
+    }
+    #[test]
+    fn test_string_repetition() {
+        let env = initial_environment();
+        assert_eq!(eval_str("'ab' * 3", env.clone()).unwrap(), Value::Str(s!("ababab")));
+        assert_eq!(eval_str("3 * 'ab'", env.clone()).unwrap(), Value::Str(s!("ababab")));
+        assert_eq!(eval_str("'-' * 2.0", env.clone()).unwrap(), Value::Str(s!("--")));
+        assert_eq!(eval_str("'ab' * 0", env.clone()).unwrap(), Value::Str(s!("")));
+        assert_eq!(eval_str("6 * 7", env.clone()).unwrap(), Value::Int(42));
+        for src in &["'ab' * -1", "'ab' * 1.5", "'ab' * 'cd'", "'ab' * true"] {
+            match eval_str(src, env.clone()) {
+                Err(Error::InvalidTypes(_)) => {},
+                res => panic!("expected InvalidTypes from {}, got {:?}", src, res),
+            }
+        }
+        assert_eq!(eval_str("'' * 9223372036854775807", env.clone()).unwrap(), Value::Str(s!("")));
+        for src in &["'ab' * 9223372036854775807", "'ab' * 1073741824"] {
+            match eval_str(src, env.clone()) {
+                Err(Error::InvalidArgument(_)) => {},
+                res => panic!("expected InvalidArgument from {}, got {:?}", src, res),
+            }
+        }
+    }
+    #[test]
+    fn test_string_slices() {
+        let env = initial_environment();
+        eval_str("s := 'hello world'", env.clone()).unwrap();
+        let cases = [
+            ("s[1:4]", "ell"),
+            ("s[:3]", "hel"),
+            ("s[2:]", "llo world"),
+            ("s[:]", "hello world"),
+            ("s[-5:]", "world"),
+            ("s[:-6]", "hello"),
+            ("s[-5:-1]", "worl"),
+            ("s[3:100]", "lo world"),
+            ("s[-100:2]", "he"),
+            ("s[4:2]", ""),
+            ("s[1.0:3]", "el"),
+            ("'🇷🇺 🇸🇹 😀'[2:]", "🇸🇹 😀"),
+        ];
+        for &(src, expected) in cases.iter() {
+            assert_eq!(eval_str(src, env.clone()).unwrap(), Value::Str(s!(expected)), "{}", src);
+        }
+        assert!(eval_str("s[0.5:2]", env.clone()).is_err());
+        assert!(eval_str("5[1:2]", env).is_err());
+    }
+    #[test]
+    fn test_contains() {
+        let env = initial_environment();
+        assert_eq!(eval_str("contains('hello world', 'o w')", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("contains('hello world', 'xyz')", env.clone()).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("contains('hello', '')", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("'ell' in 'hello'", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("'z' in 'hello' or 'h' in 'hello'", env.clone()).unwrap(), Value::Bool(true));
+        // a letter isn't found inside a letter with an accent on it
+        assert_eq!(operations::contains(&Value::Str(s!("cafe\u{301}")), &Value::Str(s!("e"))).unwrap(), Value::Bool(false));
+        for src in &["contains('hello', 1)", "1 in 'hello'", "'a' in 5", "contains('a')"] {
+            assert!(eval_str(src, env.clone()).is_err(), "{} should be an error", src);
+        }
+    }
+    #[test]
+    fn test_module_functions_keep_their_identity() {
+        let env = initial_environment();
+        load_module_into_env("use 'lib.nemo'", env.clone(), "examples").unwrap();
+        assert_eq!(eval_str("lib = lib", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("lib.my_awesome_function = lib.my_awesome_function", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("{f := lib.my_awesome_function; m := lib; f = m.my_awesome_function}", env).unwrap(), Value::Bool(true));
+    }
+    #[test]
+    fn test_read_and_write_file() {
+        let env = initial_environment();
+        let path = ::std::env::temp_dir().join("nemo_test_read_and_write_file.txt");
+        let path = path.to_str().unwrap();
+        eval_str(&format!("path := '{}'", path), env.clone()).unwrap();
+        assert_eq!(eval_str("write_file(path, 'fish\nchips')", env.clone()).unwrap(), Value::Number(0.0));
+        assert_eq!(eval_str("read_file(path)", env.clone()).unwrap(), Value::Str(s!("fish\nchips")));
+        ::std::fs::remove_file(path).unwrap();
+        match eval_str("read_file(path)", env.clone()) {
+            Err(Error::IoError(_)) => {},
+            r => panic!("expected an IoError, got {:?}", r),
+        }
+        match eval_str("write_file('/no/such/dir/file.txt', 'x')", env.clone()) {
+            Err(Error::IoError(_)) => {},
+            r => panic!("expected an IoError, got {:?}", r),
+        }
+        assert!(eval_str("read_file(1)", env).is_err());
+    }
+    #[test]
+    fn test_use_as() {
+        let env = initial_environment();
+        load_module_into_env("use 'lib.nemo' as helpers", env.clone(), "examples").unwrap();
+        assert_eq!(eval_str("helpers.my_awesome_function(1)", env.clone()).unwrap(), Value::Int(8));
+        match eval_str("lib", env) {
+            Err(Error::UndefinedName(_)) => {},
+            r => panic!("expected UndefinedName, got {:?}", r),
+        }
+        assert_eq!(module_name("dir/lib.nemo", None), "lib");
+        assert_eq!(module_name("dir/lib.nemo", Some(s!("other"))), "other");
+    }
+    #[test]
+    fn test_use_finds_modules_next_to_the_importing_file() {
+        let dir = ::std::env::temp_dir().join("nemo_test_use_relative");
+        ::std::fs::create_dir_all(dir.join("sub")).unwrap();
+        File::create(dir.join("sub/a.nemo")).unwrap().write_all(b"use 'b.nemo'\nget() => b.get() + 1").unwrap();
+        File::create(dir.join("sub/b.nemo")).unwrap().write_all(b"get() => 41").unwrap();
+        let env = initial_environment();
+        load_module_into_env("use 'sub/a.nemo'", env.clone(), dir.to_str().unwrap()).unwrap();
+        assert_eq!(eval_str("a.get()", env).unwrap(), Value::Int(42));
+        ::std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_circular_imports_are_an_error() {
+        let dir = ::std::env::temp_dir().join("nemo_test_circular_imports");
+        ::std::fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("a.nemo")).unwrap().write_all(b"use 'b.nemo'\nf() => 1").unwrap();
+        File::create(dir.join("b.nemo")).unwrap().write_all(b"use 'a.nemo'\ng() => 2").unwrap();
+        let env = initial_environment();
+        match load_module_into_env("use 'a.nemo'", env.clone(), dir.to_str().unwrap()) {
+            Err(LoadError::CircularImport(path)) => assert_eq!(path, "a.nemo"),
+            r => panic!("expected a CircularImport, got {:?}", r),
+        }
+        // Nothing is left marked as loading after the error
+        File::create(dir.join("b.nemo")).unwrap().write_all(b"g() => 2").unwrap();
+        load_module_into_env("use 'a.nemo'", env.clone(), dir.to_str().unwrap()).unwrap();
+        assert_eq!(eval_str("a.f()", env).unwrap(), Value::Int(1));
+        ::std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_diamond_imports_share_one_module() {
+        let dir = ::std::env::temp_dir().join("nemo_test_diamond_imports");
+        ::std::fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("util.nemo")).unwrap().write_all(b"one() => 1").unwrap();
+        File::create(dir.join("left.nemo")).unwrap().write_all(b"use 'util.nemo'").unwrap();
+        File::create(dir.join("right.nemo")).unwrap().write_all(b"use 'util.nemo'").unwrap();
+        let env = initial_environment();
+        load_module_into_env("use 'left.nemo'\nuse 'right.nemo'\nuse 'util.nemo'", env.clone(), dir.to_str().unwrap()).unwrap();
+        assert_eq!(eval_str("left.util = right.util", env.clone()).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("util = left.util", env.clone()).unwrap(), Value::Bool(true));
+        // A module has no top level expressions, so reading its file is the only
+        // thing loading it does. Once util.nemo has changed, only a second load
+        // would see the change, and that includes a use on another thread.
+        File::create(dir.join("util.nemo")).unwrap().write_all(b"one() => 2").unwrap();
+        let dir_name = dir.to_str().unwrap().to_owned();
+        let from_another_thread = thread::spawn(move || {
+            let env = initial_environment();
+            load_module_into_env("use 'right.nemo'\nuse 'util.nemo'", env.clone(), &dir_name).unwrap();
+            (eval_str("util", env.clone()).unwrap(), eval_str("right.util.one()", env).unwrap())
+        }).join().unwrap();
+        assert_eq!(from_another_thread.0, eval_str("util", env.clone()).unwrap());
+        assert_eq!(from_another_thread.1, Value::Int(1));
+        assert_eq!(eval_str("util.one()", env).unwrap(), Value::Int(1));
+        ::std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    #[allow(deprecated)]
+    fn test_old_environment_names_still_work() {
+        let env = initial_enviroment();
+        let frame: Enviroment = Environment::new();
+        assert!(frame.lookup("print").is_none());
+        assert_eq!(eval_str("1 + 1", env).unwrap(), Value::Int(2));
+    }
+    #[test]
+    fn test_syntax_error_in_a_used_module() {
+        let dir = ::std::env::temp_dir().join("nemo_test_module_syntax_error");
+        ::std::fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("bad.nemo")).unwrap().write_all(b"f() => 1\ng() => (").unwrap();
+        File::create(dir.join("main.nemo")).unwrap().write_all(b"use 'bad.nemo'").unwrap();
+        let env = initial_environment();
+        // The error is placed in bad.nemo, even when it's reached through main.nemo
+        match load_module_into_env("use 'main.nemo'", env, dir.to_str().unwrap()) {
+            Err(LoadError::ModuleSyntaxError(d)) => {
+                assert_eq!(d.file, dir.join("bad.nemo").to_str().unwrap());
+                assert_eq!((d.line, d.column), (Some(2), Some(9)));
+            },
+            r => panic!("expected a ModuleSyntaxError, got {:?}", r),
+        }
+        ::std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_a_module_that_fails_to_load_is_not_bound() {
+        let dir = ::std::env::temp_dir().join("nemo_test_failed_module");
+        ::std::fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("half.nemo")).unwrap().write_all(b"f() => 1\ng() => (").unwrap();
+        let env = initial_environment();
+        assert!(load_module_into_env("use 'half.nemo'", env.clone(), dir.to_str().unwrap()).is_err());
+        match eval_str("half", env.clone()) {
+            Err(Error::UndefinedName(_)) => {},
+            r => panic!("expected UndefinedName, got {:?}", r),
+        }
+        // It wasn't cached either, so once it's fixed it loads
+        File::create(dir.join("half.nemo")).unwrap().write_all(b"f() => 1\ng() => 2").unwrap();
+        load_module_into_env("use 'half.nemo'", env.clone(), dir.to_str().unwrap()).unwrap();
+        assert_eq!(eval_str("half.g()", env).unwrap(), Value::Int(2));
+        ::std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_missing_module_is_an_io_error() {
+        let env = initial_environment();
+        match load_module_into_env("use 'does_not_exist.nemo'", env, ".") {
+            Err(LoadError::IoError(path, _)) => assert_eq!(path, "does_not_exist.nemo"),
+            r => panic!("expected an IoError, got {:?}", r),
         }
     }
 }
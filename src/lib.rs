@@ -1,6 +1,7 @@
 extern crate lalrpop_util;
 extern crate bounded_spsc_queue as queue;
 extern crate unicode_segmentation;
+extern crate unicode_normalization;
 pub mod parser;
 pub mod ast;
 pub mod interpreter;
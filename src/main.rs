@@ -1,15 +1,18 @@
 extern crate nemo;
 #[macro_use]
 extern crate clap;
-extern crate bounded_spsc_queue as queue;
-use std::io::{stdin, stdout, Write};
+extern crate rustyline;
+extern crate atty;
 use std::cell::RefCell;
-use std::sync::{Arc, Mutex};
-use std::io;
+use std::sync::{Arc, Mutex, Weak};
+use std::path::{Path, PathBuf};
 use std::io::prelude::*;
 use std::fs::File;
+use std::env;
+use std::process;
 use std::thread;
 use clap::{Arg, App};
+use rustyline::error::ReadlineError;
 
 fn main() {
     let matches = App::new("nemo")
@@ -23,65 +26,140 @@ fn main() {
                                .long("repl")
                                .help("Starts the REPL")
                                .conflicts_with("INPUT"))
+                          .arg(Arg::with_name("EVAL")
+                               .short("e")
+                               .long("eval")
+                               .takes_value(true)
+                               .value_name("EXPR")
+                               .help("Evaluates EXPR, prints the result, and exits")
+                               .conflicts_with_all(&["INPUT", "REPL"]))
+                          .arg(Arg::with_name("DIAGNOSTICS")
+                               .long("diagnostics")
+                               .takes_value(true)
+                               .value_name("FORMAT")
+                               .possible_values(&["human", "json"])
+                               .default_value("human")
+                               .help("Reports errors as text for people, or as a JSON array on stderr for tools"))
                           .get_matches();
-    if matches.is_present("REPL") || matches.value_of("INPUT").is_none() {
-        repl();
-    } else {
-        run_progam_in_file(matches.value_of("INPUT").unwrap());
+    if let Ok(size) = env::var("NEMO_PIPE_BUFFER") {
+        match nemo::interpreter::parse_pipe_buffer_size(&size) {
+            Some(n) => nemo::interpreter::set_pipe_buffer_size(n),
+            None => eprintln!("Ignoring NEMO_PIPE_BUFFER={:?}, it is not a positive whole number", size),
+        }
+    }
+    if let Ok(depth) = env::var("NEMO_MAX_CALL_DEPTH") {
+        match depth.parse::<usize>() {
+            Ok(n) if n != 0 && n <= nemo::interpreter::MAX_CALL_DEPTH_LIMIT => nemo::interpreter::set_max_call_depth(n),
+            _ => eprintln!("Ignoring NEMO_MAX_CALL_DEPTH={:?}, it is not a whole number from 1 to {}", depth, nemo::interpreter::MAX_CALL_DEPTH_LIMIT),
+        }
+    }
+    let json = matches.value_of("DIAGNOSTICS") == Some("json");
+    let expr = matches.value_of("EVAL").map(String::from);
+    let input = matches.value_of("INPUT").map(String::from);
+    let start_repl = matches.is_present("REPL") || input.is_none();
+    // The main thread's stack is too small to reach the call depth limit
+    let interpreter = thread::Builder::new().stack_size(nemo::interpreter::interpreter_stack_size()).spawn(move || {
+        if let Some(expr) = expr {
+            eval_expression(&expr, json);
+        } else if start_repl {
+            repl();
+        } else {
+            run_progam_in_file(&input.unwrap(), json);
+        }
+    });
+    match interpreter {
+        Ok(interpreter) => if interpreter.join().is_err() {
+            process::exit(101);
+        },
+        Err(e) => {
+            eprintln!("error: could not make a {} byte stack for a call depth of {}: {}. Try a smaller NEMO_MAX_CALL_DEPTH.",
+                      nemo::interpreter::interpreter_stack_size(), nemo::interpreter::max_call_depth(), e);
+            process::exit(1);
+        }
     }
 }
 
+type ReplEditor = rustyline::Editor<NameCompleter>;
+
 fn repl() {
-    let env = nemo::interpreter::initial_enviroment();
-    let stdin = stdin();
-    let mut stdout = stdout();
-    let (repl_producer, consumer) = queue::make(1);
-    let (repl_producer, consumer) = (Arc::new(Mutex::new(repl_producer)), Arc::new(Mutex::new(consumer)));
-    let (producer, repl_consumer) = queue::make(1);
-    let (producer, repl_consumer) = (Arc::new(Mutex::new(producer)), Arc::new(Mutex::new(repl_consumer)));
-    let p = repl_producer.clone();
-    thread::spawn(move|| {
-        loop {
-            let lock = p.lock().unwrap();
-            match lock.try_push(nemo::interpreter::Value::FinishedPipe) {
-                Some(_) => {},
-                None => thread::sleep_ms(200),
+    // Lines piped into the REPL weren't typed by the user, so they aren't kept
+    let history = if atty::is(atty::Stream::Stdin) { history_path() } else { None };
+    let editor = Arc::new(Mutex::new(rustyline::Editor::new()));
+    let mut env = repl_environment(&editor, &history);
+    // Only the names the user defines are listed by ":env"
+    let builtins = {
+        let lock = env.lock().unwrap();
+        let names = lock.borrow().names();
+        names
+    };
+    editor.lock().unwrap().set_completer(Some(NameCompleter::new(env.clone(), builtins.clone())));
+    if let Some(ref history) = history {
+        // there is no history file the first time the REPL is run
+        let _ = editor.lock().unwrap().load_history(history);
+    }
+    let (consumer, producer) = top_level_pipes();
+    // Changed with ":floats on" and ":floats off"
+    let mut always_show_float = false;
+    let prompt = nemo::interpreter::repl_setting("NEMO_PROMPT", "> ");
+    let banner = nemo::interpreter::repl_setting("NEMO_BANNER", &format!("><> nemo v{} <><\nUse exit() or Ctrl-D to exit.", crate_version!()));
+    if !banner.is_empty() {
+        println!("{}", banner);
+    }
+    loop {
+        // The editor isn't locked while the line runs, so exit() can save the history
+        let line = editor.lock().unwrap().readline(&prompt);
+        let input = match line {
+            Ok(input) => input,
+            // Ctrl-C abandons the current line
+            Err(ReadlineError::Interrupted) => continue,
+            // EOF (Ctrl-D or the end of piped input)
+            Err(ReadlineError::Eof) => {
+                println!();
+                break;
+            },
+            Err(e) => {
+                println!("Error: {}", e);
+                break;
             }
+        };
+        if !input.trim().is_empty() {
+            editor.lock().unwrap().add_history_entry(input.trim_right());
         }
-    });
-    let c = repl_consumer.clone();
-    thread::spawn(move|| {
-        loop {
-            let lock = c.lock().unwrap();
-            match lock.try_pop() {
-                Some(_) => {},
-                None => thread::sleep_ms(200),
+        if input.trim() == ":help" {
+            print_repl_help();
+        } else if input.trim() == ":stats" {
+            println!("{}", nemo::interpreter::env_stats(&env));
+        } else if input.trim() == ":env" {
+            print_user_names(&env, &builtins);
+        } else if input.trim() == ":clear" {
+            env = repl_environment(&editor, &history);
+            editor.lock().unwrap().set_completer(Some(NameCompleter::new(env.clone(), builtins.clone())));
+        } else if input.trim() == ":type" || input.trim_left().starts_with(":type ") {
+            let expr = match nemo::parser::parse_Expr(&input.trim_left()[":type".len()..]) {
+                Ok(expr) => expr,
+                Err(e) => {
+                    println!("Error: {:?}", e);
+                    continue;
+                }
+            };
+            match type_of(&expr, env.clone(), consumer.clone(), producer.clone()) {
+                Ok(name) => println!("{}", name),
+                Err(e) => println!("Error: {:?}", e),
             }
-        }
-    });
-    println!("><> nemo v{} <><", crate_version!());
-    println!("Use Ctrl-C to exit.");
-    loop {
-        print!("> ");
-        stdout.flush().unwrap();
-        let mut input = String::new();
-        stdin.read_line(&mut input).unwrap();
-        if let Ok(nemo::ast::Top::Definition(def)) = nemo::parser::parse_Definition(&input) {
+        } else if input.trim() == ":floats on" {
+            always_show_float = true;
+        } else if input.trim() == ":floats off" {
+            always_show_float = false;
+        } else if let Ok(nemo::ast::Top::Definition(def)) = nemo::parser::parse_Definition(&input) {
             nemo::interpreter::define_function(def, env.clone());
-        } else if let Ok(nemo::ast::Top::Use(module_path)) = nemo::parser::parse_Use(&input) {
-            let mut file = File::open(&module_path).unwrap();
-            let mut contents = String::new();
-            file.read_to_string(&mut contents).unwrap();
-            let module_env = nemo::interpreter::initial_enviroment();
-            match nemo::interpreter::load_module_into_env(&contents, module_env.clone(), ".") {
-                Ok(_) => {},
-                Err(e) => println!("Syntax error in module {:?}: {:?}", module_path, e),
-            };
-            let name = ::std::path::Path::new(&module_path).file_stem().unwrap().to_str().unwrap().to_owned();
-            let lock = env.lock().unwrap();
-            lock.borrow_mut().set(name, Some(nemo::interpreter::Value::Module(module_env)));
+        } else if let Ok(nemo::ast::Top::Use(module_path, alias)) = nemo::parser::parse_Use(&input) {
+            if let Err(e) = nemo::interpreter::use_module(&module_path, alias, env.clone(), ".") {
+                println!("error: {}", e);
+            }
         } else {
-            let expr = match nemo::parser::parse_Expr(&input) {
+            // "expr;" is evaluated just for its side effects
+            let (input, quiet) = nemo::interpreter::strip_repl_semicolon(&input);
+            let expr = match nemo::parser::parse_Expr(input) {
                 Ok(expr) => expr,
                 Err(e) => {
                     println!("Error: {:?}", e);
@@ -89,44 +167,243 @@ fn repl() {
                 }
             };
             match nemo::interpreter::eval(&expr, env.clone(), consumer.clone(), producer.clone()) {
-                Ok(res) | Err(nemo::interpreter::Error::EarlyReturn(res)) => println!("{:?}", res),
+                Ok(_) | Err(nemo::interpreter::Error::EarlyReturn(_)) if quiet => {},
+                Ok(res) | Err(nemo::interpreter::Error::EarlyReturn(res)) => println!("{}", nemo::interpreter::display_for_repl(&res, always_show_float)),
                 Err(e)  => println!("Error: {:?}", e),
             };
         }
     }
+    if let Some(ref history) = history {
+        save_history(&editor.lock().unwrap(), history);
+    }
 }
 
-fn run_progam_in_file(path: &str) {
-    let mut file = File::open(path).unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-    let env = nemo::interpreter::initial_enviroment();
-    // Set up pipes
-    let (repl_producer, consumer) = queue::make(1);
-    let (repl_producer, consumer) = (Arc::new(Mutex::new(repl_producer)), Arc::new(Mutex::new(consumer)));
-    let (producer, repl_consumer) = queue::make(1);
-    let (producer, repl_consumer) = (Arc::new(Mutex::new(producer)), Arc::new(Mutex::new(repl_consumer)));
-    let p = repl_producer.clone();
-    thread::spawn(move|| {
-        loop {
-            let lock = p.lock().unwrap();
-            lock.push(nemo::interpreter::Value::FinishedPipe);
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")).map(|home| PathBuf::from(home).join(".nemo_history"))
+}
+
+fn save_history(editor: &ReplEditor, history: &Path) {
+    if let Err(e) = editor.save_history(history) {
+        println!("Could not save history: {}", e);
+    }
+}
+
+// A fresh environment for the REPL. exit() ends the process without going
+// back to the REPL's loop, so here it saves the history first.
+fn repl_environment(editor: &Arc<Mutex<ReplEditor>>, history: &Option<PathBuf>) -> Arc<Mutex<RefCell<nemo::interpreter::Environment>>> {
+    let env = nemo::interpreter::initial_environment();
+    if let Some(ref history) = *history {
+        let lock = env.lock().unwrap();
+        let exit = lock.borrow().lookup("exit").and_then(|exit| exit).unwrap();
+        // A Weak, because the editor's completer holds the environment
+        let editor: Weak<Mutex<ReplEditor>> = Arc::downgrade(editor);
+        let history = history.clone();
+        let save_then_exit = move |args: Vec<nemo::interpreter::Value>| {
+            if let Some(editor) = editor.upgrade() {
+                save_history(&editor.lock().unwrap(), &history);
+            }
+            nemo::interpreter::apply(&exit, args, nemo::interpreter::detached_consumer(), nemo::interpreter::detached_producer())
+        };
+        lock.borrow_mut().set(String::from("exit"), Some(nemo::interpreter::Value::PrimFunc(Arc::new(Box::new(save_then_exit)))));
+    }
+    env
+}
+
+// Completes the name being typed at the cursor from everything in scope,
+// builtins included. After "lib." or "lib['" it completes the names that the
+// module lib defines instead.
+struct NameCompleter {
+    env: Arc<Mutex<RefCell<nemo::interpreter::Environment>>>,
+    builtins: Vec<String>,
+}
+
+impl NameCompleter {
+    fn new(env: Arc<Mutex<RefCell<nemo::interpreter::Environment>>>, builtins: Vec<String>) -> NameCompleter {
+        NameCompleter { env, builtins }
+    }
+
+    fn module_members(&self, module: &str) -> Vec<String> {
+        let lock = self.env.lock().unwrap();
+        let found = lock.borrow().lookup(module);
+        match found {
+            Some(Some(nemo::interpreter::Value::Module(module_env))) => {
+                let lock = module_env.lock().unwrap();
+                let names = lock.borrow().frame_names();
+                names.into_iter().filter(|name| self.builtins.binary_search(name).is_err()).collect()
+            },
+            _ => vec![],
         }
-    });
-    let c = repl_consumer.clone();
-    thread::spawn(move|| {
-        loop {
-            let lock = c.lock().unwrap();
-            lock.pop();
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Where the name that ends at the end of `s` starts
+fn name_start(s: &str) -> usize {
+    match s.char_indices().rev().find(|&(_, c)| !is_name_char(c)) {
+        Some((i, c)) => i + c.len_utf8(),
+        None => 0,
+    }
+}
+
+impl rustyline::completion::Completer for NameCompleter {
+    fn complete(&self, line: &str, pos: usize) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let start = name_start(before);
+        let prefix = &before[start..];
+        if prefix.chars().next().map_or(false, |c| c.is_ascii_digit()) {
+            return Ok((start, vec![]));
         }
-    });
+        let rest = &before[..start];
+        let module = if rest.ends_with('.') {
+            Some(&rest[..rest.len() - 1])
+        } else if rest.ends_with("['") {
+            Some(&rest[..rest.len() - 2])
+        } else {
+            None
+        };
+        let candidates = match module {
+            Some(module) => self.module_members(&module[name_start(module)..]),
+            // Every name would match an empty prefix
+            None if prefix.is_empty() => return Ok((start, vec![])),
+            None => {
+                let lock = self.env.lock().unwrap();
+                let names = lock.borrow().names();
+                names
+            },
+        };
+        Ok((start, candidates.into_iter().filter(|name| name.starts_with(prefix)).collect()))
+    }
+}
+
+fn print_repl_help() {
+    println!(":help          show this list");
+    println!(":env           list the names you have defined");
+    println!(":type EXPR     show the type of EXPR");
+    println!(":stats         count the names you have defined");
+    println!(":clear         forget everything you have defined");
+    println!(":floats on     always show the decimal point on floats");
+    println!(":floats off    hide it on whole floats again");
+}
+
+fn print_user_names(env: &Arc<Mutex<RefCell<nemo::interpreter::Environment>>>, builtins: &[String]) {
+    let lock = env.lock().unwrap();
+    let env = lock.borrow();
+    for (name, val) in env.bindings() {
+        if builtins.binary_search(&name).is_ok() {
+            continue;
+        }
+        println!("{} = {}", name, nemo::interpreter::display_for_error(&val));
+    }
+}
+
+fn type_name(v: &nemo::interpreter::Value) -> &'static str {
+    use nemo::interpreter::Value;
+    match *v {
+        Value::Number(_) => "float",
+        Value::Int(_) => "int",
+        Value::Str(_) => "string",
+        Value::Bool(_) => "bool",
+        Value::PrimFunc(_) | Value::PipeFunc(_) | Value::UserFunc(..) => "function",
+        Value::FinishedPipe => "FinishedPipe",
+        Value::Module(_) => "module",
+    }
+}
+
+// Literals and lambdas have a type that can be found without running anything.
+// Every other expression is evaluated, side effects and all.
+fn type_of(expr: &nemo::ast::Expr,
+           env: Arc<Mutex<RefCell<nemo::interpreter::Environment>>>,
+           consumer: nemo::interpreter::PipeConsumer,
+           producer: nemo::interpreter::PipeProducer) -> Result<&'static str, nemo::interpreter::Error<'static>> {
+    use nemo::ast::Expr;
+    match *expr {
+        Expr::Number(_) => Ok("float"),
+        Expr::Int(_) => Ok("int"),
+        Expr::Str(_) | Expr::Stringify(_) => Ok("string"),
+        Expr::Bool(_) => Ok("bool"),
+        Expr::Lambda(..) => Ok("function"),
+        Expr::FinishedPipe => Ok("FinishedPipe"),
+        _ => match nemo::interpreter::eval(expr, env, consumer, producer) {
+            Ok(res) | Err(nemo::interpreter::Error::EarlyReturn(res)) => Ok(type_name(&res)),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+fn run_progam_in_file(path: &str, json: bool) {
+    let mut contents = String::new();
+    if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+        let message = format!("could not open file '{}': {}", path, e);
+        if json {
+            exit_with_diagnostics(&[nemo::interpreter::diagnostic_without_place(path, message)]);
+        }
+        eprintln!("error: {}", message);
+        process::exit(1);
+    }
+    let env = nemo::interpreter::initial_environment();
+    let (consumer, producer) = top_level_pipes();
     match nemo::interpreter::load_module_into_env(&contents, env.clone(), ::std::path::Path::new(path).parent().unwrap().to_str().unwrap()) {
         Ok(_) => {},
-        Err(e) => println!("Syntax Error: {:?}", e),
+        Err(nemo::interpreter::LoadError::ParseError(ref e)) if json => {
+            exit_with_diagnostics(&[nemo::interpreter::parse_error_diagnostic(path, &contents, e)]);
+        },
+        Err(nemo::interpreter::LoadError::ParseError(e)) => println!("Syntax Error: {:?}", e),
+        Err(nemo::interpreter::LoadError::ModuleSyntaxError(ref d)) if json => exit_with_diagnostics(&[d.clone()]),
+        Err(e) => {
+            if json {
+                exit_with_diagnostics(&[nemo::interpreter::diagnostic_without_place(path, e.to_string())]);
+            }
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
     };
     let nemo_main = nemo::parser::parse_Expr("main()").unwrap();
     match nemo::interpreter::eval(&nemo_main, env, consumer, producer) {
         Ok(_) => {},
+        Err(ref e) if json => exit_with_diagnostics(&[nemo::interpreter::diagnostic_without_place(path, format!("{:?}", e))]),
         Err(e) => println!("Runtime Error: {:?}", e),
     };
+    if json {
+        exit_with_diagnostics(&[]);
+    }
+}
+
+fn eval_expression(input: &str, json: bool) {
+    let expr = match nemo::parser::parse_Expr(input) {
+        Ok(expr) => expr,
+        Err(ref e) if json => exit_with_diagnostics(&[nemo::interpreter::parse_error_diagnostic("<eval>", input, e)]),
+        Err(e) => {
+            eprintln!("Syntax Error: {:?}", e);
+            process::exit(1);
+        }
+    };
+    let env = nemo::interpreter::initial_environment();
+    let (consumer, producer) = top_level_pipes();
+    match nemo::interpreter::eval(&expr, env, consumer, producer) {
+        Ok(res) | Err(nemo::interpreter::Error::EarlyReturn(res)) => println!("{:?}", res),
+        Err(ref e) if json => exit_with_diagnostics(&[nemo::interpreter::diagnostic_without_place("<eval>", format!("{:?}", e))]),
+        Err(e) => {
+            eprintln!("Runtime Error: {:?}", e);
+            process::exit(1);
+        }
+    };
+    if json {
+        exit_with_diagnostics(&[]);
+    }
+}
+
+// With --diagnostics json the array is always written, even when it is empty,
+// and the exit status is 1 if it isn't.
+fn exit_with_diagnostics(diagnostics: &[nemo::interpreter::Diagnostic]) -> ! {
+    eprintln!("{}", nemo::interpreter::diagnostics_to_json(diagnostics));
+    process::exit(if diagnostics.is_empty() { 0 } else { 1 });
+}
+
+// Code that runs outside of a pipeline still needs pipe endpoints,
+// so pulls get FinishedPipe and pushes are a PushedToNone error.
+fn top_level_pipes() -> (nemo::interpreter::PipeConsumer, nemo::interpreter::PipeProducer) {
+    (nemo::interpreter::detached_consumer(), nemo::interpreter::detached_producer())
 }
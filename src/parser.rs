@@ -1,9 +1,74 @@
 include!("grammar.rs"); // auto-generated by lalrpop
 
+// Turns the inside of a double quoted string into literal chunks and the
+// expressions in its ${...}s, joined with +. \${ is a literal ${, and \" and
+// \\ are a quote and a backslash.
+fn interpolate(s: &str) -> Result<Expr, &'static str> {
+    let mut parts = vec![];
+    let mut chunk = String::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, '$')) => chunk.push('$'),
+                Some((_, '"')) => chunk.push('"'),
+                Some((_, '\\')) => chunk.push('\\'),
+                Some((_, other)) => {
+                    chunk.push('\\');
+                    chunk.push(other);
+                }
+                None => chunk.push('\\'),
+            },
+            '$' if chars.peek().map(|&(_, c)| c) == Some('{') => {
+                chars.next();
+                // Find the matching }, skipping over any in nested blocks or strings
+                let start = i + 2;
+                let mut depth = 1;
+                let mut in_str = false;
+                let mut end = None;
+                for (j, c) in chars.by_ref() {
+                    match c {
+                        '\'' => in_str = !in_str,
+                        '{' if !in_str => depth += 1,
+                        '}' if !in_str => {
+                            depth -= 1;
+                            if depth == 0 {
+                                end = Some(j);
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                let end = match end {
+                    Some(end) => end,
+                    None => return Err("unclosed ${ in string"),
+                };
+                let expr = match parse_Expr(&s[start..end]) {
+                    Ok(expr) => expr,
+                    Err(_) => return Err("invalid expression in ${...} in string"),
+                };
+                if !chunk.is_empty() {
+                    parts.push(Box::new(Expr::Str(chunk)));
+                    chunk = String::new();
+                }
+                parts.push(Box::new(Expr::Stringify(expr)));
+            }
+            c => chunk.push(c),
+        }
+    }
+    if !chunk.is_empty() || parts.is_empty() {
+        parts.push(Box::new(Expr::Str(chunk)));
+    }
+    let mut parts = parts.into_iter();
+    let first = parts.next().unwrap();
+    Ok(*parts.fold(first, |acc, part| Box::new(Expr::Binary(acc, Op::Plus, part))))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ast::{Expr, Op};
+    use ast::{Expr, Op, Pattern, Top, Definition, Prototype};
 
     fn s(x: &str) -> String {
         String::from(x)
@@ -11,20 +76,93 @@ mod tests {
 
     #[test]
     fn test_parsing_number() {
-        let expected = Box::new(Expr::Number(22.0));
+        let expected = Box::new(Expr::Int(22));
         assert_eq!(parse_Expr("22").unwrap(), expected);
         assert_eq!(parse_Expr("(22)").unwrap(), expected);
         assert_eq!(parse_Expr("((((((22))))))").unwrap(), expected);
+        assert_eq!(parse_Expr("22.5").unwrap(), Box::new(Expr::Number(22.5)));
+        assert_eq!(parse_Expr("22.0").unwrap(), Box::new(Expr::Number(22.0)));
+    }
+    #[test]
+    fn test_integer_literal_overflow() {
+        assert_eq!(parse_Expr("9223372036854775807").unwrap(), Box::new(Expr::Int(9223372036854775807)));
+        match parse_Expr("12345678901234567890") {
+            Err(ParseError::User { .. }) => {},
+            other => panic!("expected a user error, got {:?}", other),
+        }
+        assert!(parse_Expr("-12345678901234567890").is_err());
     }
     #[test]
     fn test_parsing_expressions() {
-        let expected = Box::new(Expr::Binary(Box::new(Expr::Number(22.0)),
+        let expected = Box::new(Expr::Binary(Box::new(Expr::Int(22)),
                                              Op::Plus,
                                              Box::new(Expr::Binary(
                                                  Box::new(Expr::Name(s("foo"))),
                                                  Op::Times,
-                                                 Box::new(Expr::Number(2.0))))));
+                                                 Box::new(Expr::Int(2))))));
         assert_eq!(parse_Expr("22+foo*2").unwrap(), expected);
+        let expected = Box::new(Expr::Binary(Box::new(Expr::Int(7)), Op::DoubleSlash, Box::new(Expr::Int(2))));
+        assert_eq!(parse_Expr("7 // 2").unwrap(), expected);
+    }
+    #[test]
+    fn test_bitwise_parsing() {
+        let expected = Box::new(Expr::Binary(
+            Box::new(Expr::Name(s("a"))),
+            Op::BitOr,
+            Box::new(Expr::Binary(
+                Box::new(Expr::Name(s("b"))),
+                Op::BitXor,
+                Box::new(Expr::Binary(
+                    Box::new(Expr::Name(s("c"))),
+                    Op::BitAnd,
+                    Box::new(Expr::Binary(Box::new(Expr::Name(s("d"))), Op::Shl, Box::new(Expr::Int(1))))))))));
+        assert_eq!(parse_Expr("a || b ^ c & d << 1").unwrap(), expected);
+        let expected = Box::new(Expr::Binary(
+            Box::new(Expr::Binary(Box::new(Expr::Name(s("x"))), Op::Shr, Box::new(Expr::Int(2)))),
+            Op::Lesser,
+            Box::new(Expr::Int(3))));
+        assert_eq!(parse_Expr("x >> 2 < 3").unwrap(), expected);
+        assert_eq!(parse_Expr("|| -> 1").unwrap(), Box::new(Expr::Lambda(vec![], Box::new(Expr::Int(1)))));
+    }
+    #[test]
+    fn test_power_parsing() {
+        let expected = Box::new(Expr::Binary(Box::new(Expr::Int(2)),
+                                             Op::Power,
+                                             Box::new(Expr::Binary(
+                                                 Box::new(Expr::Int(3)),
+                                                 Op::Power,
+                                                 Box::new(Expr::Int(2))))));
+        assert_eq!(parse_Expr("2 ** 3 ** 2").unwrap(), expected);
+        let expected = Box::new(Expr::Binary(Box::new(Expr::Int(2)),
+                                             Op::Times,
+                                             Box::new(Expr::Binary(
+                                                 Box::new(Expr::Name(s("x"))),
+                                                 Op::Power,
+                                                 Box::new(Expr::Int(2))))));
+        assert_eq!(parse_Expr("2 * x ** 2").unwrap(), expected);
+        let expected = Box::new(Expr::Neg(Box::new(Expr::Binary(Box::new(Expr::Int(2)),
+                                                                Op::Power,
+                                                                Box::new(Expr::Int(2))))));
+        assert_eq!(parse_Expr("-2 ** 2").unwrap(), expected);
+        let expected = Box::new(Expr::Binary(Box::new(Expr::Int(2)),
+                                             Op::Power,
+                                             Box::new(Expr::Neg(Box::new(Expr::Int(1))))));
+        assert_eq!(parse_Expr("2 ** -1").unwrap(), expected);
+    }
+    #[test]
+    fn test_negation_parsing() {
+        assert_eq!(parse_Expr("-x").unwrap(), Box::new(Expr::Neg(Box::new(Expr::Name(s("x"))))));
+        let expected = Box::new(Expr::Binary(Box::new(Expr::Name(s("a"))),
+                                             Op::Minus,
+                                             Box::new(Expr::Neg(Box::new(Expr::Binary(
+                                                 Box::new(Expr::Name(s("b"))),
+                                                 Op::Plus,
+                                                 Box::new(Expr::Int(1))))))));
+        assert_eq!(parse_Expr("a - -(b + 1)").unwrap(), expected);
+        let expected = Box::new(Expr::Binary(Box::new(Expr::Name(s("a"))), Op::Minus, Box::new(Expr::Name(s("b")))));
+        assert_eq!(parse_Expr("a-b").unwrap(), expected);
+        let expected = Box::new(Expr::Neg(Box::new(Expr::Call(Box::new(Expr::Name(s("f"))), vec![Box::new(Expr::Name(s("x")))]))));
+        assert_eq!(parse_Expr("-f(x)").unwrap(), expected);
     }
     #[test]
     fn test_parsing_names() {
@@ -33,22 +171,49 @@ mod tests {
         assert_eq!(parse_Expr("(foo)").unwrap(), expected);
     }
     #[test]
+    fn test_unicode_name_parsing() {
+        assert_eq!(parse_Expr("número").unwrap(), Box::new(Expr::Name(s("número"))));
+        let expected = Box::new(Expr::Assignment(s("größe"), Box::new(Expr::Int(3))));
+        assert_eq!(parse_Expr("größe := 3").unwrap(), expected);
+        let expected = Box::new(Expr::Call(Box::new(Expr::Name(s("平方"))), vec![Box::new(Expr::Name(s("x_1")))]));
+        assert_eq!(parse_Expr("平方(x_1)").unwrap(), expected);
+        assert!(parse_Definition("cuadrado(número) => número * número").is_ok());
+        // keywords, operators and digits still aren't names
+        assert_eq!(parse_Expr("while 1 do 2").unwrap(), Box::new(Expr::While(Box::new(Expr::Int(1)), Box::new(Expr::Int(2)))));
+        assert_eq!(parse_Expr("whileé").unwrap(), Box::new(Expr::Name(s("whileé"))));
+        assert!(parse_Expr("1abc").is_err());
+        assert!(parse_Expr("→").is_err());
+        let expected = Box::new(Expr::Binary(Box::new(Expr::Name(s("ñ"))), Op::Minus, Box::new(Expr::Name(s("é")))));
+        assert_eq!(parse_Expr("ñ-é").unwrap(), expected);
+    }
+    #[test]
+    fn test_quoted_name_parsing() {
+        let expected = Box::new(Expr::Block(vec![
+                            Box::new(Expr::Assignment(s("while"), Box::new(Expr::Int(1)))),
+                            Box::new(Expr::Name(s("while")))]));
+        assert_eq!(parse_Expr("{`while` := 1; `while`}").unwrap(), expected);
+        let expected = Box::new(Expr::Index(Box::new(Expr::Name(s("m"))), Box::new(Expr::Str(s("if")))));
+        assert_eq!(parse_Expr("m.`if`").unwrap(), expected);
+        assert_eq!(parse_Expr("`foo`").unwrap(), Box::new(Expr::Name(s("foo"))));
+        assert!(parse_Expr("``").is_err());
+    }
+    #[test]
     fn test_call_parsing() {
         let expected = Box::new(Expr::Call(Box::new(Expr::Name(s("foo"))), vec![]));
         assert_eq!(parse_Expr("foo()").unwrap(), expected);
-        let expected = Box::new(Expr::Call(Box::new(Expr::Name(s("foo"))), vec![Box::new(Expr::Number(1.0))]));
+        let expected = Box::new(Expr::Call(Box::new(Expr::Name(s("foo"))), vec![Box::new(Expr::Int(1))]));
         assert_eq!(parse_Expr("foo(1)").unwrap(), expected);
-        let expected = Box::new(Expr::Call(Box::new(Expr::Name(s("foo"))), vec![Box::new(Expr::Number(1.0)),
-                                                                     Box::new(Expr::Number(2.0)),
-                                                                     Box::new(Expr::Number(3.0))]));
+        let expected = Box::new(Expr::Call(Box::new(Expr::Name(s("foo"))), vec![Box::new(Expr::Int(1)),
+                                                                     Box::new(Expr::Int(2)),
+                                                                     Box::new(Expr::Int(3))]));
         assert_eq!(parse_Expr("foo(1, 2, 3)").unwrap(), expected);
-        let expected = Box::new(Expr::Call(Box::new(Expr::Name(s("foo"))), vec![Box::new(Expr::Binary(Box::new(Expr::Number(1.0)), Op::Plus, Box::new(Expr::Number(2.0))))]));
+        let expected = Box::new(Expr::Call(Box::new(Expr::Name(s("foo"))), vec![Box::new(Expr::Binary(Box::new(Expr::Int(1)), Op::Plus, Box::new(Expr::Int(2))))]));
         assert_eq!(parse_Expr("foo(1 + 2)").unwrap(), expected);
-        let expected = Box::new(Expr::Call(Box::new(Expr::Name(s("foo"))), vec![Box::new(Expr::Binary(Box::new(Expr::Number(1.0)), Op::Plus, Box::new(Expr::Number(2.0)))),
-                                                                     Box::new(Expr::Number(3.0))]));
+        let expected = Box::new(Expr::Call(Box::new(Expr::Name(s("foo"))), vec![Box::new(Expr::Binary(Box::new(Expr::Int(1)), Op::Plus, Box::new(Expr::Int(2)))),
+                                                                     Box::new(Expr::Int(3))]));
         assert_eq!(parse_Expr("foo(1 + 2, 3)").unwrap(), expected);
-        let expected = Box::new(Expr::Call(Box::new(Expr::Binary(Box::new(Expr::Number(1.0)), Op::Plus, Box::new(Expr::Number(2.0)))), vec![Box::new(Expr::Binary(Box::new(Expr::Number(1.0)), Op::Plus, Box::new(Expr::Number(2.0)))),
-                                                                     Box::new(Expr::Number(3.0))]));
+        let expected = Box::new(Expr::Call(Box::new(Expr::Binary(Box::new(Expr::Number(1.0)), Op::Plus, Box::new(Expr::Int(2)))), vec![Box::new(Expr::Binary(Box::new(Expr::Int(1)), Op::Plus, Box::new(Expr::Int(2)))),
+                                                                     Box::new(Expr::Int(3))]));
         assert_eq!(parse_Expr("(1.0 + 2)(1 + 2, 3)").unwrap(), expected);
     }
     #[test]
@@ -57,9 +222,16 @@ mod tests {
                                              Box::new(Expr::Binary(
                                                       Box::new(Expr::Name(s("x"))),
                                                       Op::Plus,
-                                                      Box::new(Expr::Number(1.0)
+                                                      Box::new(Expr::Int(1)
                                                   )))));
         assert_eq!(parse_Expr(r"|x, y| -> (x + 1)").unwrap(), expected);
+        let expected = Box::new(Expr::Lambda(vec![s("x")],
+                                             Box::new(Expr::Binary(
+                                                      Box::new(Expr::Name(s("x"))),
+                                                      Op::Plus,
+                                                      Box::new(Expr::Int(1)
+                                                  )))));
+        assert_eq!(parse_Expr(r"|x| -> x + 1").unwrap(), expected);
         let expected = Box::new(Expr::Lambda(vec![s("x")],
                                              Box::new(Expr::Binary(
                                                       Box::new(Expr::Name(s("x"))),
@@ -67,13 +239,12 @@ mod tests {
                                                       Box::new(Expr::Number(1.0)
                                                   )))));
         assert_eq!(parse_Expr(r"x -> (x + 1.0)").unwrap(), expected);
-        assert_eq!(parse_Expr(r"|x| -> x + 1").unwrap(), expected);
     }
     #[test]
     fn test_pipe_parsing() {
         let expected = Box::new(Expr::Binary(
                             Box::new(Expr::Binary(
-                                Box::new(Expr::Call(Box::new(Expr::Name(s("range"))), vec![Box::new(Expr::Number(10.0))])),
+                                Box::new(Expr::Call(Box::new(Expr::Name(s("range"))), vec![Box::new(Expr::Int(10))])),
                                 Op::Pipe,
                                 Box::new(Expr::Call(Box::new(Expr::Name(s("map"))), vec![Box::new(Expr::Lambda(vec![s("x")], Box::new(Expr::Binary(Box::new(Expr::Name(s("x"))), Op::Times, Box::new(Expr::Name(s("x")))))))]))
                             )),
@@ -83,36 +254,93 @@ mod tests {
         assert_eq!(got, expected);
     }
     #[test]
+    fn test_range_parsing() {
+        let expected = Box::new(Expr::Range(Box::new(Expr::Int(1)), Box::new(Expr::Int(5)), false));
+        assert_eq!(parse_Expr("1..5").unwrap(), expected);
+        let expected = Box::new(Expr::Range(Box::new(Expr::Int(1)), Box::new(Expr::Name(s("n"))), true));
+        assert_eq!(parse_Expr("1..=n").unwrap(), expected);
+        let expected = Box::new(Expr::Binary(
+                            Box::new(Expr::Range(Box::new(Expr::Int(0)), Box::new(Expr::Int(3)), false)),
+                            Op::Pipe,
+                            Box::new(Expr::Call(Box::new(Expr::Name(s("show_pipe"))), vec![]))));
+        assert_eq!(parse_Expr("(0..3) | show_pipe()").unwrap(), expected);
+        let expected = Box::new(Expr::Range(
+                            Box::new(Expr::Int(0)),
+                            Box::new(Expr::Binary(Box::new(Expr::Name(s("n"))), Op::Minus, Box::new(Expr::Int(1)))),
+                            false));
+        assert_eq!(parse_Expr("0..n-1").unwrap(), expected);
+        // Without parentheses this would be 1..(4 | f()), so it isn't allowed
+        assert!(parse_Expr("1..4 | f()").is_err());
+        assert!(parse_Expr("1..=4 | f()").is_err());
+        let expected = Box::new(Expr::Range(
+                            Box::new(Expr::Int(1)),
+                            Box::new(Expr::Binary(
+                                Box::new(Expr::Int(4)),
+                                Op::Pipe,
+                                Box::new(Expr::Call(Box::new(Expr::Name(s("f"))), vec![])))),
+                            false));
+        assert_eq!(parse_Expr("1..(4 | f())").unwrap(), expected);
+    }
+    #[test]
     fn test_assignment_parsing() {
-        let expected = Box::new(Expr::Assignment(s("spam"), Box::new(Expr::Number(1.0))));
+        let expected = Box::new(Expr::Assignment(s("spam"), Box::new(Expr::Int(1))));
         let got = parse_Expr("spam := 1").unwrap();
         assert_eq!(got, expected);
     }
     #[test]
+    fn test_compound_assignment_parsing() {
+        let expected = Box::new(Expr::Assignment(s("i"), Box::new(Expr::Binary(
+                                    Box::new(Expr::Name(s("i"))),
+                                    Op::Plus,
+                                    Box::new(Expr::Int(1))))));
+        assert_eq!(parse_Expr("i += 1").unwrap(), expected);
+        let expected = Box::new(Expr::Assignment(s("x"), Box::new(Expr::Binary(
+                                    Box::new(Expr::Name(s("x"))),
+                                    Op::Times,
+                                    Box::new(Expr::Binary(Box::new(Expr::Int(2)), Op::Plus, Box::new(Expr::Int(3))))))));
+        assert_eq!(parse_Expr("x *= 2 + 3").unwrap(), expected);
+        assert!(parse_Expr("i -= 1").is_ok());
+        assert!(parse_Expr("i /= 2").is_ok());
+        assert!(parse_Expr("1 += 1").is_err());
+        assert!(parse_Expr("f(x) += 1").is_err());
+    }
+    #[test]
     fn test_push_parsing() {
-        let expected = Box::new(Expr::Push(Box::new(Expr::Number(1.0))));
+        let expected = Box::new(Expr::Push(Box::new(Expr::Int(1))));
         let got = parse_Expr("push 1").unwrap();
         assert_eq!(got, expected);
     }
     #[test]
     fn test_block_parsing() {
         let expected = Box::new(Expr::Block(
-                                vec![Box::new(Expr::Assignment(s("spam"), Box::new(Expr::Number(1.0)))),
-                                     Box::new(Expr::Push(Box::new(Expr::Number(1.0))))]));
+                                vec![Box::new(Expr::Assignment(s("spam"), Box::new(Expr::Int(1)))),
+                                     Box::new(Expr::Push(Box::new(Expr::Int(1))))]));
         let got = parse_Expr(r"{spam := 1; push 1}").unwrap();
         assert_eq!(expected, got);
     }
     #[test]
+    fn test_ternary_parsing() {
+        assert_eq!(parse_Expr("1 ? 2 : 3").unwrap(), parse_Expr("if 1 then 2 else 3").unwrap());
+        assert_eq!(parse_Expr("a > b ? a : b").unwrap(), parse_Expr("if a > b then a else b").unwrap());
+        assert_eq!(parse_Expr("a ? b : c ? d : e").unwrap(), parse_Expr("if a then b else if c then d else e").unwrap());
+        let expected = Box::new(Expr::If(Box::new(Expr::Name(s("a"))),
+                                         Box::new(Expr::Name(s("b"))),
+                                         Box::new(Expr::If(Box::new(Expr::Name(s("c"))),
+                                                           Box::new(Expr::Name(s("d"))),
+                                                           Box::new(Expr::Name(s("e")))))));
+        assert_eq!(parse_Expr("a ? b : c ? d : e").unwrap(), expected);
+    }
+    #[test]
     fn test_if_else_parsing() {
-        let expected = Box::new(Expr::If(Box::new(Expr::Number(1.0)), Box::new(Expr::Number(2.0)), Box::new(Expr::Number(3.0))));
+        let expected = Box::new(Expr::If(Box::new(Expr::Int(1)), Box::new(Expr::Int(2)), Box::new(Expr::Int(3))));
         let got = parse_Expr(r"if 1 then 2 else 3").unwrap();
         assert_eq!(expected, got);
         let expected = Box::new(Expr::Lambda(vec![s("x")],
                                              Box::new(
                                                  Expr::If(
-                                                     Box::new(Expr::Number(1.0)),
-                                                     Box::new(Expr::Number(2.0)),
-                                                     Box::new(Expr::Number(3.0))
+                                                     Box::new(Expr::Int(1)),
+                                                     Box::new(Expr::Int(2)),
+                                                     Box::new(Expr::Int(3))
                                                  )
                                              )
                                          ));
@@ -121,15 +349,133 @@ mod tests {
     }
     #[test]
     fn test_while_parsing() {
-        let expected = Box::new(Expr::While(Box::new(Expr::Number(1.0)), Box::new(Expr::Number(2.0))));
+        let expected = Box::new(Expr::While(Box::new(Expr::Int(1)), Box::new(Expr::Int(2))));
         let got = parse_Expr(r"while 1 do 2").unwrap();
         assert_eq!(expected, got);
     }
     #[test]
+    fn test_do_while_parsing() {
+        let expected = Box::new(Expr::DoWhile(Box::new(Expr::Int(1)), Box::new(Expr::Int(2))));
+        assert_eq!(parse_Expr(r"do 1 while 2").unwrap(), expected);
+        let expected = Box::new(Expr::DoWhile(Box::new(Expr::While(Box::new(Expr::Name(s("a"))), Box::new(Expr::Name(s("b"))))),
+                                              Box::new(Expr::Name(s("c")))));
+        assert_eq!(parse_Expr(r"do while a do b while c").unwrap(), expected);
+    }
+    #[test]
+    fn test_try_parsing() {
+        let expected = Box::new(Expr::Try(Box::new(Expr::Call(Box::new(Expr::Name(s("f"))), vec![])),
+                                          Box::new(Expr::Assignment(s("done"), Box::new(Expr::Bool(true))))));
+        assert_eq!(parse_Expr(r"try f() finally done := true").unwrap(), expected);
+        assert!(parse_Expr(r"try f()").is_err());
+    }
+    #[test]
+    fn test_break_continue_parsing() {
+        let expected = Box::new(Expr::While(Box::new(Expr::Bool(true)), Box::new(Expr::Break)));
+        assert_eq!(parse_Expr(r"while true do break").unwrap(), expected);
+        let expected = Box::new(Expr::While(Box::new(Expr::Bool(true)), Box::new(Expr::Continue)));
+        assert_eq!(parse_Expr(r"while true do continue").unwrap(), expected);
+    }
+    #[test]
+    fn test_match_parsing() {
+        let expected = Box::new(Expr::Match(Box::new(Expr::Name(s("x"))), vec![
+                            (Pattern::Number(1.0), Box::new(Expr::Str(s("one")))),
+                            (Pattern::Str(s("two")), Box::new(Expr::Int(2))),
+                            (Pattern::Bool(true), Box::new(Expr::Bool(false))),
+                            (Pattern::Number(-3.0), Box::new(Expr::Int(3))),
+                            (Pattern::Wildcard, Box::new(Expr::Binary(Box::new(Expr::Name(s("x"))), Op::Plus, Box::new(Expr::Int(1))))),
+                        ]));
+        assert_eq!(parse_Expr(r"match x { 1 => 'one', 'two' => 2, true => false, -3 => 3, _ => x + 1 }").unwrap(), expected);
+        assert!(parse_Expr(r"match x { y => 1 }").is_err());
+        // _ is still an ordinary name outside of patterns
+        assert_eq!(parse_Expr(r"_").unwrap(), Box::new(Expr::Name(s("_"))));
+    }
+    #[test]
+    fn test_use_parsing() {
+        assert_eq!(parse_Use("use 'lib.nemo'").unwrap(), Top::Use(s("lib.nemo"), None));
+        assert_eq!(parse_Use("use 'a/lib.nemo' as helpers").unwrap(), Top::Use(s("a/lib.nemo"), Some(s("helpers"))));
+        assert!(parse_Use("use 'lib.nemo' as").is_err());
+    }
+    #[test]
+    fn test_interpolated_string_parsing() {
+        assert_eq!(parse_Expr("\"hello\"").unwrap(), Box::new(Expr::Str(s("hello"))));
+        assert_eq!(parse_Expr("\"\"").unwrap(), Box::new(Expr::Str(s(""))));
+        let expected = Box::new(Expr::Binary(Box::new(Expr::Str(s("hi "))), Op::Plus, Box::new(Expr::Stringify(Box::new(Expr::Name(s("name")))))));
+        assert_eq!(parse_Expr("\"hi ${name}\"").unwrap(), expected);
+        let expected = Box::new(Expr::Binary(
+            Box::new(Expr::Binary(
+                Box::new(Expr::Binary(
+                    Box::new(Expr::Stringify(Box::new(Expr::Name(s("a"))))),
+                    Op::Plus,
+                    Box::new(Expr::Str(s(" + 1 = "))))),
+                Op::Plus,
+                Box::new(Expr::Stringify(Box::new(Expr::Binary(Box::new(Expr::Name(s("a"))), Op::Plus, Box::new(Expr::Int(1)))))))),
+            Op::Plus,
+            Box::new(Expr::Str(s("!")))));
+        assert_eq!(parse_Expr("\"${a} + 1 = ${a + 1}!\"").unwrap(), expected);
+        assert_eq!(parse_Expr("\"cost: \\${5}\"").unwrap(), Box::new(Expr::Str(s("cost: ${5}"))));
+        assert_eq!(parse_Expr("\"${ {1; '}'} }\"").unwrap(), Box::new(Expr::Stringify(Box::new(Expr::Block(vec![Box::new(Expr::Int(1)), Box::new(Expr::Str(s("}")))])))));
+        assert!(parse_Expr("\"${1 +}\"").is_err());
+        assert!(parse_Expr("\"${1\"").is_err());
+    }
+    #[test]
+    fn test_block_body_definition() {
+        assert_eq!(parse_Definition("f(x) { y := x * 2; y + 1 }").unwrap(),
+                   parse_Definition("f(x) => { y := x * 2; y + 1 }").unwrap());
+        let program = parse_Program("f(x) { x }\ng() => f(1)").unwrap();
+        assert_eq!(program.len(), 2);
+    }
+    #[test]
+    fn test_default_arguments() {
+        let def = match parse_Definition("add(x, y=1, z='a' + 'b') => x").unwrap() {
+            Top::Definition(def) => def,
+            top => panic!("expected a definition, got {:?}", top),
+        };
+        assert_eq!(def.prototype.args, vec![s("x"), s("y"), s("z")]);
+        assert_eq!(def.prototype.defaults, vec![
+            Box::new(Expr::Int(1)),
+            Box::new(Expr::Binary(Box::new(Expr::Str(s("a"))), Op::Plus, Box::new(Expr::Str(s("b"))))),
+        ]);
+        assert_eq!(def.prototype.required_args(), 1);
+        assert!(parse_Definition("add(x=1, y) => x").is_err());
+    }
+    #[test]
+    fn test_named_arguments() {
+        assert_eq!(parse_Expr("f(1, y: 2, z: a ? 3 : 4)").unwrap(), Box::new(Expr::NamedCall(
+            Box::new(Expr::Name(s("f"))),
+            vec![Box::new(Expr::Int(1))],
+            vec![
+                (s("y"), Box::new(Expr::Int(2))),
+                (s("z"), Box::new(Expr::If(Box::new(Expr::Name(s("a"))), Box::new(Expr::Int(3)), Box::new(Expr::Int(4))))),
+            ])));
+        // without named arguments it is still a plain call
+        assert_eq!(parse_Expr("f(1, 2)").unwrap(), Box::new(Expr::Call(
+            Box::new(Expr::Name(s("f"))),
+            vec![Box::new(Expr::Int(1)), Box::new(Expr::Int(2))])));
+        assert!(parse_Expr("f(x: 1, 2)").is_err());
+    }
+    #[test]
+    fn test_slices() {
+        let name = |n: &str| Box::new(Expr::Name(s(n)));
+        assert_eq!(parse_Expr("s[1:4]").unwrap(), Box::new(Expr::Slice(name("s"), Some(Box::new(Expr::Int(1))), Some(Box::new(Expr::Int(4))))));
+        assert_eq!(parse_Expr("s[:3]").unwrap(), Box::new(Expr::Slice(name("s"), None, Some(Box::new(Expr::Int(3))))));
+        assert_eq!(parse_Expr("s[2:]").unwrap(), Box::new(Expr::Slice(name("s"), Some(Box::new(Expr::Int(2))), None)));
+        assert_eq!(parse_Expr("s[:]").unwrap(), Box::new(Expr::Slice(name("s"), None, None)));
+        assert_eq!(parse_Expr("s[-2:]").unwrap(), Box::new(Expr::Slice(name("s"), Some(Box::new(Expr::Neg(Box::new(Expr::Int(2))))), None)));
+        // a ternary inside brackets is still an index
+        assert_eq!(parse_Expr("s[c ? 1 : 2]").unwrap(), Box::new(Expr::Index(name("s"),
+            Box::new(Expr::If(name("c"), Box::new(Expr::Int(1)), Box::new(Expr::Int(2)))))));
+    }
+    #[test]
     fn test_program_parsing() {
-        let expected = r#"[Definition { prototype: Prototype { name: "add", args: ["x"] }, body: Binary(Name("x"), Plus, Number(1)) }, Definition { prototype: Prototype { name: "bar", args: ["y"] }, body: Binary(Name("y"), Times, Number(2)) }]"#;
-        let got = format!("{:?}", parse_Program(r"add(x) => x + 1
-        bar(y) => y * 2").unwrap());
-        assert_eq!(got, expected);
+        let expected = vec![
+            Top::Definition(Definition::new(
+                Prototype::new(s("add"), vec![s("x")]),
+                Box::new(Expr::Binary(Box::new(Expr::Name(s("x"))), Op::Plus, Box::new(Expr::Int(1)))))),
+            Top::Definition(Definition::new(
+                Prototype::new(s("bar"), vec![s("y")]),
+                Box::new(Expr::Binary(Box::new(Expr::Name(s("y"))), Op::Times, Box::new(Expr::Int(2)))))),
+        ];
+        assert_eq!(parse_Program(r"add(x) => x + 1
+        bar(y) => y * 2").unwrap(), expected);
     }
 }